@@ -1,11 +1,11 @@
 use crate::game::{PlayerArr, PlayerArrExt};
 use {
     crate::{
-        bit_set::{BitIndex, FullBitIndexMap, INDICES},
+        bit_set::{BitIndex, BitIndexSet, FullBitIndexMap, INDICES},
         game::{
             rendering::VIEW_SIZE,
-            room::{Coord, Room, ShortestPaths},
-            PlayerColor, World, PLAYER_SIZE, ZERO_POS,
+            room::{Coord, Room, ShortestPaths, CELL_SIZE},
+            PlayerColor, World,
         },
         prelude::*,
     },
@@ -15,7 +15,7 @@ use {
 pub struct PathLengthsAi {
     my_color: PlayerColor,
     sp: ShortestPaths,
-    next_step: Coord,
+    steering: Steering,
 }
 
 #[derive(Debug)]
@@ -25,9 +25,73 @@ pub struct SinkAi {
     sink_to_teleporters: FullBitIndexMap<u8>,
     sink_to_prey: FullBitIndexMap<u8>,
     sink_to_predator: FullBitIndexMap<u8>,
-    goal: Coord,
+    steering: Steering,
     prey_maybe_at: Coord,
     predator_maybe_at: Coord,
+    prey_model: OpponentModel,
+    predator_model: OpponentModel,
+}
+// Learns how one player tends to move, from what I see of them during this match
+#[derive(Debug)]
+pub struct OpponentModel {
+    color: PlayerColor, // final
+    was_at: Coord,      // where I last saw them
+    came_from: Option<Direction>,
+    unseen_ticks: u32,
+    near_tele: bool,
+    // statistics
+    ticks: u32,
+    idle_ticks: u32,
+    turns: [u32; Turn::COUNT],
+    steps_near_tele: u32,
+    teleported: u32,
+    threatened_steps: u32,
+    fled: u32,
+}
+#[derive(Copy, Clone)]
+enum Turn {
+    Straight = 0,
+    Right = 1,
+    Back = 2,
+    Left = 3,
+}
+// What to expect of being relocated by a teleporter, as done by `Entities::random_free_space`
+struct TeleOdds {
+    // mean path distance from each coord to a uniformly random free space
+    avg_dist_to_free: FullBitIndexMap<u16>,
+    // mean path distance between two uniformly random free spaces
+    avg_free_to_free: u16,
+    // where the teleporters were when I was computed. Stale once any of them moves
+    teleporters: Vec<Pos>,
+}
+// What an AI-controlled player does for their team, once a color has several players
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Role {
+    Chaser,  // runs the prey down
+    Blocker, // holds the articulation point the prey would escape through
+    Flanker, // comes at the prey from the side away from the chaser
+}
+// Plans for all the AI-controlled players of one color together
+pub struct TeamPlanner {
+    my_color: PlayerColor, // final
+    sp: ShortestPaths,
+    articulation_points: BitIndexSet,
+    // shared fog-of-war. The prey is observed while ANY teammate sees them
+    prey_model: OpponentModel,
+}
+// Plays as its team's planner tells it to. Each color has one player for now, so its team is
+// itself, and it always chases
+pub struct TeamAi {
+    my_color: PlayerColor,
+    planner: TeamPlanner,
+    steering: Steering,
+}
+#[derive(Debug)]
+struct Steering {
+    // the coord whose center I am moving toward
+    waypoint: Coord,
+    // where my predator was when I last planned
+    predator_was_at: Coord,
 }
 ////////////////////////////////////////////////////////////
 pub trait Ai {
@@ -38,24 +102,340 @@ pub trait AiExt: Ai {
     fn new(my_color: PlayerColor, world: &World, rng: &mut Rng) -> Self;
 }
 
+// a predator this close that moves to another cell causes a replan, even mid-cell
+const THREAT_DIST: Size = CELL_SIZE.scalar_mul(2);
+const MAX_PREDICT_STEPS: u32 = 6;
+
 ///////////////////
-fn diff_to_vel(diff: Pos) -> Vel {
-    let mut vel = Vel::default();
-    if diff != ZERO_POS {
-        let dim = if diff[X].distance_from_zero() > diff[Y].distance_from_zero() { X } else { Y };
-        vel[dim] = diff[dim].sign();
-    }
-    vel
+fn diff_to_vel(diff: Pos, move_size: Size) -> Vel {
+    // move along every axis that isn't already within half a step of the goal
+    Vel::new_xy_with(|dim| {
+        if diff[dim].distance_from_zero() * 2 < move_size[dim] {
+            None
+        } else {
+            diff[dim].sign()
+        }
+    })
+}
+fn step_toward(sp: &ShortestPaths, room: &Room, from: Coord, goal: Coord) -> Coord {
+    let goal_bi = goal.bit_index();
+    let dist_to_goal = sp.coord_pair_path_dist([from.bit_index(), goal_bi]).unwrap();
+    Direction::iter_domain()
+        .filter_map(|dir| from.stepped_in_room(room, dir))
+        .find(|closer_maybe| {
+            let dist_here = sp.coord_pair_path_dist([closer_maybe.bit_index(), goal_bi]).unwrap();
+            dist_here + 1 == dist_to_goal
+        })
+        .unwrap_or(from)
 }
 fn coord_dist([a, b]: [Coord; 2]) -> Size {
     (a.corner_pos() - b.corner_pos()).distances_from_zero()
 }
+fn coord_dist_sum(pair: [Coord; 2]) -> u32 {
+    let dist = coord_dist(pair);
+    dist[X] as u32 + dist[Y] as u32
+}
+fn at_tele(world: &World, coord: Coord) -> bool {
+    world.entities.teleporters.iter().any(|&pos| Coord::from_pos_flooring(pos) == coord)
+}
+fn can_see(world: &World, me_at: Coord, color: PlayerColor) -> bool {
+    let player_size = world.rules.player_size();
+    coord_dist([coord_of_player(world, color), me_at]) < VIEW_SIZE + player_size.scalar_div(2)
+}
+// Laplace-smoothed estimate of the ratio hits/tries
+fn smoothed_ratio(hits: u32, tries: u32) -> f32 {
+    (hits + 1) as f32 / (tries + 2) as f32
+}
 fn pos_of_player(world: &World, color: PlayerColor) -> Pos {
     world.entities.players[color].pos
 }
 fn coord_of_player(world: &World, color: PlayerColor) -> Coord {
     Coord::from_pos_flooring(pos_of_player(world, color))
 }
+impl Turn {
+    const COUNT: usize = 4;
+    fn between(from: Direction, to: Direction) -> Self {
+        let clockwise_index = |dir| match dir {
+            Up => 0,
+            Right => 1,
+            Down => 2,
+            Left => 3,
+        };
+        match (clockwise_index(to) + 4 - clockwise_index(from)) % 4 {
+            0 => Self::Straight,
+            1 => Self::Right,
+            2 => Self::Back,
+            _ => Self::Left,
+        }
+    }
+}
+impl OpponentModel {
+    pub fn new(color: PlayerColor, world: &World) -> Self {
+        Self {
+            color,
+            was_at: coord_of_player(world, color),
+            came_from: None,
+            unseen_ticks: 0,
+            near_tele: false,
+            ticks: 0,
+            idle_ticks: 0,
+            turns: [0; Turn::COUNT],
+            steps_near_tele: 0,
+            teleported: 0,
+            threatened_steps: 0,
+            fled: 0,
+        }
+    }
+    fn near_tele(world: &World, at: Coord) -> bool {
+        Direction::iter_domain()
+            .filter_map(|dir| at.stepped_in_room(&world.room, dir))
+            .any(|dest| at_tele(world, dest))
+    }
+    fn threatened_at(&self, world: &World, at: Coord) -> Option<Coord> {
+        let predator_at = coord_of_player(world, self.color.predator());
+        if coord_dist([predator_at, at]) < THREAT_DIST {
+            Some(predator_at)
+        } else {
+            None
+        }
+    }
+    // call every tick that I can see this player
+    pub fn observe(&mut self, world: &World) {
+        let player = &world.entities.players[self.color];
+        let at = Coord::from_pos_flooring(player.pos);
+        if self.unseen_ticks > 0 {
+            // they moved while I wasn't looking. nothing to learn about this step
+            self.unseen_ticks = 0;
+            self.came_from = None;
+        } else {
+            self.ticks += 1;
+            if player.vel[X].is_none() && player.vel[Y].is_none() {
+                self.idle_ticks += 1;
+            }
+            if at != self.was_at {
+                let step_dir = Direction::iter_domain().find(|&dir| self.was_at.stepped(dir) == at);
+                if self.near_tele {
+                    self.steps_near_tele += 1;
+                    // teleporting moves you far away, and the teleporter with you
+                    if step_dir.is_none() || at_tele(world, at) {
+                        self.teleported += 1;
+                    }
+                }
+                if let Some(step_dir) = step_dir {
+                    if let Some(came_from) = self.came_from {
+                        self.turns[Turn::between(came_from, step_dir) as usize] += 1;
+                    }
+                    if let Some(predator_at) = self.threatened_at(world, self.was_at) {
+                        self.threatened_steps += 1;
+                        if coord_dist_sum([predator_at, at])
+                            > coord_dist_sum([predator_at, self.was_at])
+                        {
+                            self.fled += 1;
+                        }
+                    }
+                }
+                self.came_from = step_dir;
+            }
+        }
+        self.was_at = at;
+        self.near_tele = Self::near_tele(world, at);
+    }
+    // call every tick that I can NOT see this player
+    pub fn unobserved(&mut self) {
+        self.unseen_ticks += 1;
+    }
+    // relative likelihoods of the player's next step from `at` in each direction, indexed by Direction
+    fn step_weights(&self, world: &World, at: Coord, came_from: Option<Direction>) -> [f32; 4] {
+        let tele_ratio = smoothed_ratio(self.teleported, self.steps_near_tele);
+        let flee_ratio = smoothed_ratio(self.fled, self.threatened_steps);
+        let predator_at = self.threatened_at(world, at);
+        let mut weights = [0.; 4];
+        for dir in Direction::iter_domain() {
+            if let Some(dest) = at.stepped_in_room(&world.room, dir) {
+                let mut weight = match came_from {
+                    Some(came_from) => {
+                        (self.turns[Turn::between(came_from, dir) as usize] + 1) as f32
+                    }
+                    None => 1.,
+                };
+                if at_tele(world, dest) {
+                    weight *= 2. * tele_ratio;
+                }
+                if let Some(predator_at) = predator_at {
+                    let flees =
+                        coord_dist_sum([predator_at, dest]) > coord_dist_sum([predator_at, at]);
+                    weight *= 2. * if flees { flee_ratio } else { 1. - flee_ratio };
+                }
+                weights[dir as usize] = weight;
+            }
+        }
+        let total: f32 = weights.iter().sum();
+        if total > 0. {
+            for weight in weights.iter_mut() {
+                *weight /= total;
+            }
+        }
+        weights
+    }
+    // probability of the player being in each cell `steps` cell-steps after I last saw them
+    pub fn predict(&self, world: &World, steps: u32) -> FullBitIndexMap<f32> {
+        // index 4 for "arrived here without a known direction"
+        type DirProbs = [f32; 5];
+        let stay_ratio = smoothed_ratio(self.idle_ticks, self.ticks);
+        let mut probs = FullBitIndexMap::<DirProbs>::new_copied([0.; 5]);
+        probs[self.was_at.bit_index()][self.came_from.map(|dir| dir as usize).unwrap_or(4)] = 1.;
+        for _ in 0..steps {
+            let mut next = FullBitIndexMap::<DirProbs>::new_copied([0.; 5]);
+            for bi in BitIndex::iter_domain() {
+                let at = Coord::from_bit_index(bi);
+                for came_from_index in 0..5 {
+                    let p = probs[bi][came_from_index];
+                    if p == 0. {
+                        continue;
+                    }
+                    let came_from =
+                        Direction::iter_domain().find(|&dir| dir as usize == came_from_index);
+                    next[bi][came_from_index] += p * stay_ratio;
+                    let weights = self.step_weights(world, at, came_from);
+                    for dir in Direction::iter_domain() {
+                        let weight = weights[dir as usize];
+                        if weight > 0. {
+                            let dest_bi = at.stepped(dir).bit_index();
+                            next[dest_bi][dir as usize] += p * (1. - stay_ratio) * weight;
+                        }
+                    }
+                }
+            }
+            probs = next;
+        }
+        let mut cell_probs = FullBitIndexMap::new_copied(0.);
+        for bi in BitIndex::iter_domain() {
+            cell_probs[bi] = probs[bi].iter().sum();
+        }
+        cell_probs
+    }
+    pub fn most_likely_at(&self, world: &World) -> Coord {
+        if self.unseen_ticks == 0 {
+            return self.was_at;
+        }
+        let steps = (self.unseen_ticks / world.rules.move_speed as u32).min(MAX_PREDICT_STEPS);
+        let probs = self.predict(world, steps);
+        BitIndex::iter_domain()
+            .max_by_key(|&bi| OrderedFloat(probs[bi]))
+            .map(Coord::from_bit_index)
+            .unwrap()
+    }
+}
+impl TeleOdds {
+    fn new(sp: &ShortestPaths, world: &World) -> Self {
+        let free: Vec<BitIndex> =
+            world.entities.free_space_coords().map(Coord::bit_index).collect();
+        let avg_to_free = |bi: BitIndex| {
+            let total: u32 = free
+                .iter()
+                .map(|&free_bi| sp.coord_pair_path_dist([bi, free_bi]).unwrap() as u32)
+                .sum();
+            (total / free.len().max(1) as u32) as u16
+        };
+        let mut avg_dist_to_free = FullBitIndexMap::new_copied(0);
+        for bi in BitIndex::iter_domain() {
+            avg_dist_to_free[bi] = avg_to_free(bi);
+        }
+        let total: u32 = free.iter().map(|&bi| avg_dist_to_free[bi] as u32).sum();
+        let avg_free_to_free = (total / free.len().max(1) as u32) as u16;
+        let teleporters = world.entities.teleporters.clone();
+        Self { avg_dist_to_free, avg_free_to_free, teleporters }
+    }
+    // free space also keeps clear of players, but they move all the time. Teleporters rarely do
+    fn is_stale(&self, world: &World) -> bool {
+        self.teleporters != world.entities.teleporters
+    }
+}
+impl Steering {
+    fn new(world: &World, my_color: PlayerColor) -> Self {
+        Self {
+            waypoint: coord_of_player(world, my_color),
+            predator_was_at: coord_of_player(world, my_color.predator()),
+        }
+    }
+    // returns the coord I am in IF I should (re)plan my path now
+    fn wants_plan(&self, world: &World, my_color: PlayerColor) -> Option<Coord> {
+        let me_at = coord_of_player(world, my_color);
+        let arrived = {
+            let vel = self.vel(world, my_color);
+            vel[X].is_none() && vel[Y].is_none()
+        };
+        let threatened = {
+            let predator_at = coord_of_player(world, my_color.predator());
+            predator_at != self.predator_was_at && coord_dist([predator_at, me_at]) < THREAT_DIST
+        };
+        if arrived || threatened {
+            Some(me_at)
+        } else {
+            None
+        }
+    }
+    // `path` are the next two steps from `here`.
+    fn follow(&mut self, world: &World, my_color: PlayerColor, here: Coord, path: [Coord; 2]) {
+        self.predator_was_at = coord_of_player(world, my_color.predator());
+        self.waypoint =
+            if Self::can_cut_corner(&world.room, here, path) { path[1] } else { path[0] };
+    }
+    // true IFF path turns a corner, and the 2x2 block of cells containing it has no walls inside.
+    // moving in a straight line across the block can then never touch a wall.
+    fn can_cut_corner(room: &Room, here: Coord, [a, b]: [Coord; 2]) -> bool {
+        let dir_to = |src: Coord, dest: Coord| {
+            Direction::iter_domain().find(move |&dir| src.stepped(dir) == dest)
+        };
+        if let [Some(d1), Some(d2)] = [dir_to(here, a), dir_to(a, b)] {
+            d1.dim() != d2.dim()
+                && ArrIter::new([(here, d1), (a, d2), (here, d2), (here.stepped(d2), d1)])
+                    .all(|(src, dir)| src.stepped_in_room(room, dir).is_some())
+        } else {
+            false
+        }
+    }
+    fn vel(&self, world: &World, my_color: PlayerColor) -> Vel {
+        let pos = pos_of_player(world, my_color);
+        let diff = self.waypoint.center_pos() - pos;
+        let vel = diff_to_vel(diff, world.rules.move_size());
+        if vel[X].is_none() || vel[Y].is_none() || Self::clear_of_walls(world, pos, diff) {
+            return vel;
+        }
+        // a diagonal would clip a wall. First line up with the waypoint along the shorter axis
+        let cells = diff.in_cells();
+        let shorter = if cells.x.abs() < cells.y.abs() { X } else { Y };
+        let mut vel = vel;
+        vel[!shorter] = None;
+        vel
+    }
+    // true IFF my circle moving straight by `diff` from `from` stays clear of walls, as no walls
+    // separate the cells it passes over (or comes close to)
+    fn clear_of_walls(world: &World, from: Pos, diff: Pos) -> bool {
+        let rules = &world.rules;
+        let margin =
+            Size::new_xy_with(|dim| rules.player_size()[dim] / 2 + rules.wall_size(!dim)[dim] / 2);
+        let lo = Pos::new_xy_with(|dim| {
+            let d: i16 = diff[dim].into();
+            from[dim] + d.min(0) - margin[dim]
+        });
+        let lo_coord = Coord::from_pos_flooring(lo);
+        let counts = DimMap::new_xy_with(|dim| {
+            let span = diff[dim].distance_from_zero() as u32 + margin[dim] as u32 * 2;
+            let into_cell = (lo - lo_coord.corner_pos())[dim].distance_from_zero() as u32;
+            (into_cell + span) / CELL_SIZE[dim] as u32 + 1
+        });
+        (0..counts[X]).all(|i| {
+            (0..counts[Y]).all(|j| {
+                let mut cell = lo_coord;
+                (0..i).for_each(|_| cell = cell.stepped(Right));
+                (0..j).for_each(|_| cell = cell.stepped(Down));
+                (i + 1 == counts[X] || cell.stepped_in_room(&world.room, Right).is_some())
+                    && (j + 1 == counts[Y] || cell.stepped_in_room(&world.room, Down).is_some())
+            })
+        })
+    }
+}
 impl AiExt for SinkAi {
     fn new(my_color: PlayerColor, world: &World, _rng: &mut Rng) -> Self {
         let me_at = coord_of_player(world, my_color);
@@ -64,15 +444,17 @@ impl AiExt for SinkAi {
             sink_to_teleporters: FullBitIndexMap::new_copied(u8::MAX),
             sink_to_prey: FullBitIndexMap::new_copied(u8::MAX),
             sink_to_predator: FullBitIndexMap::new_copied(u8::MAX),
-            goal: me_at,
+            steering: Steering::new(world, my_color),
             prey_maybe_at: me_at,
             predator_maybe_at: me_at,
+            prey_model: OpponentModel::new(my_color.prey(), world),
+            predator_model: OpponentModel::new(my_color.predator(), world),
         }
     }
 }
 impl Ai for SinkAi {
     fn i_was_moved(&mut self, world: &World) {
-        self.goal = Coord::from_pos_flooring(world.entities.players[self.my_color].pos);
+        self.steering = Steering::new(world, self.my_color);
         for sink_map in ArrIter::new([
             &mut self.sink_to_teleporters,
             &mut self.sink_to_prey,
@@ -82,15 +464,18 @@ impl Ai for SinkAi {
         }
     }
     fn update(&mut self, world: &World, rng: &mut Rng) -> Vel {
-        let pos_centered = {
-            let pos = pos_of_player(world, self.my_color);
-            let coord = Coord::from_pos_flooring(pos);
-            pos == coord.center_pos()
-        };
-        if pos_centered {
-            self.update_model_and_goal(world, rng);
+        let me_at = coord_of_player(world, self.my_color);
+        for model in ArrIter::new([&mut self.prey_model, &mut self.predator_model]) {
+            if can_see(world, me_at, model.color) {
+                model.observe(world);
+            } else {
+                model.unobserved();
+            }
         }
-        diff_to_vel(self.goal.center_pos() - pos_of_player(world, self.my_color))
+        if let Some(here) = self.steering.wants_plan(world, self.my_color) {
+            self.update_model_and_path(world, rng, here);
+        }
+        self.steering.vel(world, self.my_color)
     }
 }
 impl SinkAi {
@@ -111,28 +496,18 @@ impl SinkAi {
         }
         a
     }
-    // only call if pos-centered
-    fn update_model_and_goal(&mut self, world: &World, rng: &mut Rng) {
+    fn update_model_and_path(&mut self, world: &World, rng: &mut Rng, here: Coord) {
         if rng.gen_bits(4) == 0 {
-            self.update_model(world, rng);
+            self.update_model(world);
         }
-        self.update_goal(world);
+        let a = self.choose_step(world, here);
+        let b = self.choose_step(world, a);
+        self.steering.follow(world, self.my_color, here, [a, b]);
     }
-    fn update_model(&mut self, world: &World, rng: &mut Rng) {
-        let me_at = coord_of_player(world, self.my_color);
-        let mut try_update_estimated_at = |color, estimated_at: &mut Coord| {
-            let real_coord = coord_of_player(world, color);
-            let dist = coord_dist([real_coord, me_at]);
-            if dist < VIEW_SIZE + PLAYER_SIZE.scalar_div(2) {
-                // I can see you!
-                *estimated_at = real_coord;
-            } else if dist < VIEW_SIZE.scalar_div(2) {
-                // You're not here!
-                *estimated_at = Coord::random(rng);
-            }
-        };
-        try_update_estimated_at(self.my_color.prey(), &mut self.prey_maybe_at);
-        try_update_estimated_at(self.my_color.predator(), &mut self.predator_maybe_at);
+    fn update_model(&mut self, world: &World) {
+        // where I see them, or where I expect them to have gone since
+        self.prey_maybe_at = self.prey_model.most_likely_at(world);
+        self.predator_maybe_at = self.predator_model.most_likely_at(world);
 
         // recompute pred/prey sink trees
         self.sink_to_prey = Self::sink_map_to(world, self.prey_maybe_at, true);
@@ -147,21 +522,20 @@ impl SinkAi {
             .map(Coord::from_pos_flooring)
             .map(|coord| Self::sink_map_to(world, coord, false))
             .reduce(Self::reduce_sink_maps)
-            .unwrap();
+            // no teleporters. Nowhere is closer to one
+            .unwrap_or_else(|| FullBitIndexMap::new_copied(u8::MAX));
     }
-    // only call if pos-centered
-    fn update_goal(&mut self, world: &World) {
-        let me_at = coord_of_player(world, self.my_color);
-        let bi = me_at.bit_index();
-        self.goal = if self.sink_to_prey[bi] <= self.sink_to_predator[bi] {
-            Self::sink_toward(me_at, &world.room, &self.sink_to_prey)
+    fn choose_step(&self, world: &World, from: Coord) -> Coord {
+        let bi = from.bit_index();
+        if self.sink_to_prey[bi] <= self.sink_to_predator[bi] {
+            Self::sink_toward(from, &world.room, &self.sink_to_prey)
         } else {
             if self.sink_to_teleporters[bi] < self.sink_to_predator[bi] {
-                Self::sink_toward(me_at, &world.room, &self.sink_to_teleporters)
+                Self::sink_toward(from, &world.room, &self.sink_to_teleporters)
             } else {
-                Self::sink_away(me_at, &world.room, &self.sink_to_predator)
+                Self::sink_away(from, &world.room, &self.sink_to_predator)
             }
-        };
+        }
     }
     fn sink_map_to(world: &World, sink: Coord, avoid_teleporters: bool) -> FullBitIndexMap<u8> {
         let mut map = FullBitIndexMap::new_copied(u8::MAX);
@@ -198,33 +572,22 @@ impl SinkAi {
 
 impl Ai for PathLengthsAi {
     fn update(&mut self, world: &World, _rng: &mut Rng) -> Vel {
-        let my_pos = pos_of_player(world, self.my_color);
-        let already_at_next_step = { my_pos == self.next_step.center_pos() };
-        if already_at_next_step {
-            let me_bi = self.next_step.bit_index();
+        if let Some(here) = self.steering.wants_plan(world, self.my_color) {
             let goal = self.new_goal(world);
-            let goal_bi = goal.bit_index();
-            let dist_to_goal = self.sp.coord_pair_path_dist([me_bi, goal_bi]).unwrap();
-            self.next_step = Direction::iter_domain()
-                .filter_map(|dir| self.next_step.stepped_in_room(&world.room, dir))
-                .find(|closer_maybe| {
-                    let dist_here =
-                        self.sp.coord_pair_path_dist([closer_maybe.bit_index(), goal_bi]).unwrap();
-                    dist_here == dist_to_goal - 1
-                })
-                .unwrap_or(self.next_step);
+            let a = step_toward(&self.sp, &world.room, here, goal);
+            let b = step_toward(&self.sp, &world.room, a, goal);
+            self.steering.follow(world, self.my_color, here, [a, b]);
         }
-        diff_to_vel(self.next_step.center_pos() - my_pos)
+        self.steering.vel(world, self.my_color)
     }
     fn i_was_moved(&mut self, world: &World) {
-        let me_at = coord_of_player(world, self.my_color);
-        self.next_step = me_at;
+        self.steering = Steering::new(world, self.my_color);
     }
 }
 impl AiExt for PathLengthsAi {
     fn new(my_color: PlayerColor, world: &World, _rng: &mut Rng) -> Self {
         Self {
-            next_step: coord_of_player(world, my_color),
+            steering: Steering::new(world, my_color),
             sp: ShortestPaths::new(&world.room),
             my_color,
         }
@@ -235,24 +598,20 @@ impl PathLengthsAi {
         let pair_dist = move |pair: [BitIndex; 2]| {
             self.sp.coord_pair_path_dist(pair).map(|x| x as i32).unwrap_or(i32::MAX)
         };
-        let tele_bit_indices = [
-            //  ok
-            Coord::from_pos_flooring(world.entities.teleporters[0]).bit_index(),
-            // Coord::from_pos_flooring(world.entities.teleporters[1]).bit_index(),
-            // Coord::from_pos_flooring(world.entities.teleporters[2]).bit_index(),
-            // Coord::from_pos_flooring(world.entities.teleporters[3]).bit_index(),
-        ];
         let tele_dist_at = move |bi: BitIndex| {
-            tele_bit_indices
+            world
+                .entities
+                .teleporters
                 .iter()
-                .map(move |&tele_bi| {
+                .map(move |&pos| {
+                    let tele_bi = Coord::from_pos_flooring(pos).bit_index();
                     self.sp
                         .coord_pair_path_dist([bi, tele_bi])
                         .map(|x| x as i32)
                         .unwrap_or(i32::MAX)
                 })
                 .min()
-                .unwrap()
+                .unwrap_or(0) // no teleporters to be near
         };
         let me_bi = coord_of_player(world, self.my_color).bit_index();
         let prey_bi = coord_of_player(world, self.my_color.prey()).bit_index();
@@ -275,44 +634,38 @@ impl PathLengthsAi {
 
 pub struct MiniMaxAi {
     my_color: PlayerColor,
-    next_step: Coord,
+    steering: Steering,
     sp: ShortestPaths,
+    odds: TeleOdds, // cached. Expensive to compute
 }
 impl Ai for MiniMaxAi {
     fn update(&mut self, world: &World, _rng: &mut Rng) -> Vel {
-        let my_pos = pos_of_player(world, self.my_color);
-        let already_at_next_step = { my_pos == self.next_step.center_pos() };
-        if already_at_next_step {
-            let at = self.next_step;
-            let me_bi = self.next_step.bit_index();
-            let goal = {
+        if let Some(here) = self.steering.wants_plan(world, self.my_color) {
+            if self.odds.is_stale(world) {
+                self.odds = TeleOdds::new(&self.sp, world);
+            }
+            let ret = {
                 let coords = PlayerArr::new_with(|col| coord_of_player(world, col));
-                let ret = q_rec(&self.sp, world, self.my_color, coords, 3 * 3);
-                ret.next_dir.map(|dir| at.stepped(dir)).unwrap_or(at)
+                q_rec(&self.sp, &self.odds, world, self.my_color, coords, 3 * 3)
             };
-            let goal_bi = goal.bit_index();
-            let dist_to_goal = self.sp.coord_pair_path_dist([me_bi, goal_bi]).unwrap();
-            self.next_step = Direction::iter_domain()
-                .filter_map(|dir| at.stepped_in_room(&world.room, dir))
-                .find(|closer_maybe| {
-                    let dist_here =
-                        self.sp.coord_pair_path_dist([closer_maybe.bit_index(), goal_bi]).unwrap();
-                    dist_here == dist_to_goal - 1
-                })
-                .unwrap_or(self.next_step);
+            // first step as chosen by minimax. second step toward where minimax expects me to end up
+            let a = ret.next_dir.map(|dir| here.stepped(dir)).unwrap_or(here);
+            let b = step_toward(&self.sp, &world.room, a, ret.end_up[self.my_color]);
+            self.steering.follow(world, self.my_color, here, [a, b]);
         }
-        diff_to_vel(self.next_step.center_pos() - my_pos)
+        self.steering.vel(world, self.my_color)
     }
     fn i_was_moved(&mut self, world: &World) {
-        let me_at = coord_of_player(world, self.my_color);
-        self.next_step = me_at;
+        self.steering = Steering::new(world, self.my_color);
     }
 }
 impl AiExt for MiniMaxAi {
     fn new(my_color: PlayerColor, world: &World, _rng: &mut Rng) -> Self {
+        let sp = ShortestPaths::new(&world.room);
         Self {
-            next_step: coord_of_player(world, my_color),
-            sp: ShortestPaths::new(&world.room),
+            steering: Steering::new(world, my_color),
+            odds: TeleOdds::new(&sp, world),
+            sp,
             my_color,
         }
     }
@@ -323,6 +676,7 @@ struct Ret {
 }
 fn q_rec(
     sp: &ShortestPaths,
+    odds: &TeleOdds,
     world: &World,
     me: PlayerColor,
     coords: PlayerArr<Coord>,
@@ -330,44 +684,35 @@ fn q_rec(
 ) -> Ret {
     if let Some(deeper_depth) = depth_to_go.checked_sub(1) {
         // recursive case
-        let at_tele = move |coord| {
-            world.entities.teleporters.iter().any(|&pos| Coord::from_pos_flooring(pos) == coord)
-        };
-        let mut best = q_rec(sp, world, me.prey(), coords, deeper_depth);
+        let at_tele = move |coord| at_tele(world, coord);
+        let mut best = q_rec(sp, odds, world, me.prey(), coords, deeper_depth);
         for dir in Direction::iter_domain() {
             if let Some(dest) = coords[me].stepped_in_room(&world.room, dir) {
                 let mut new_coords = coords;
                 new_coords[me] = dest;
                 let cannot_continue = new_coords[me] == new_coords[me.prey()]
                     || new_coords[me] == new_coords[me.predator()]
-                    || world
-                        .entities
-                        .teleporters
-                        .iter()
-                        .any(|&pos| Coord::from_pos_flooring(pos) == new_coords[me]);
+                    || at_tele(new_coords[me]);
                 let new_end_up = if cannot_continue {
                     new_coords
                 } else {
                     // recursive call
-                    q_rec(sp, world, me.prey(), new_coords, deeper_depth).end_up
+                    q_rec(sp, odds, world, me.prey(), new_coords, deeper_depth).end_up
                 };
                 let new_best = {
                     let h_value = move |end_up: &PlayerArr<Coord>| {
-                        if at_tele(end_up[me]) {
-                            0
-                        } else {
-                            let me_bi = end_up[me].bit_index();
-                            const AVG_DIST: u16 = 15;
-                            let col_dist = |col: PlayerColor| {
-                                if at_tele(end_up[col]) {
-                                    AVG_DIST
-                                } else {
-                                    sp.coord_pair_path_dist([me_bi, end_up[col].bit_index()])
-                                        .unwrap()
-                                }
-                            };
-                            col_dist(me.predator()) - col_dist(me.prey())
-                        }
+                        // whoever ends up on a teleporter will be somewhere random instead
+                        let dist = |a: Coord, b: Coord| -> i32 {
+                            let [a_bi, b_bi] = [a.bit_index(), b.bit_index()];
+                            (match [at_tele(a), at_tele(b)] {
+                                [false, false] => sp.coord_pair_path_dist([a_bi, b_bi]).unwrap(),
+                                [true, false] => odds.avg_dist_to_free[b_bi],
+                                [false, true] => odds.avg_dist_to_free[a_bi],
+                                [true, true] => odds.avg_free_to_free,
+                            }) as i32
+                        };
+                        dist(end_up[me], end_up[me.predator()])
+                            - dist(end_up[me], end_up[me.prey()])
                     };
                     h_value(&new_end_up) > h_value(&best.end_up)
                 };
@@ -383,3 +728,219 @@ fn q_rec(
         Ret { end_up: coords, next_dir: None }
     }
 }
+
+impl TeamPlanner {
+    pub fn new(my_color: PlayerColor, world: &World) -> Self {
+        Self {
+            my_color,
+            sp: ShortestPaths::new(&world.room),
+            articulation_points: world.room.articulation_points(),
+            prey_model: OpponentModel::new(my_color.prey(), world),
+        }
+    }
+    // call every tick, with where each of my teammates is
+    pub fn observe(&mut self, world: &World, team: &[Coord]) {
+        if team.iter().any(|&at| can_see(world, at, self.my_color.prey())) {
+            self.prey_model.observe(world);
+        } else {
+            self.prey_model.unobserved();
+        }
+    }
+    fn dist(&self, a: Coord, b: Coord) -> u16 {
+        self.sp.coord_pair_path_dist([a.bit_index(), b.bit_index()]).unwrap_or(u16::MAX)
+    }
+    // the cells `from` reaches without passing through `without`
+    fn reachable_without(room: &Room, from: Coord, without: Coord) -> BitIndexSet {
+        let mut seen = BitIndexSet::default();
+        seen.insert(without.bit_index());
+        seen.insert(from.bit_index());
+        let mut bfs_queue = VecDeque::new();
+        bfs_queue.push_back(from);
+        while let Some(at) = bfs_queue.pop_front() {
+            for dest in Direction::iter_domain().filter_map(|dir| at.stepped_in_room(room, dir)) {
+                if seen.insert(dest.bit_index()) {
+                    bfs_queue.push_back(dest);
+                }
+            }
+        }
+        seen
+    }
+    // articulation points through which the prey can get away from the chaser, nearest first.
+    // The prey reaches one without passing the chaser. Removing it leaves the prey and chaser
+    // together, and cuts off everything beyond it
+    fn escapes(&self, world: &World, prey_at: Coord, chaser_at: Coord) -> Vec<Coord> {
+        let room = &world.room;
+        let ahead_of_chaser = Self::reachable_without(room, prey_at, chaser_at);
+        let mut escapes: Vec<Coord> = self
+            .articulation_points
+            .iter()
+            .filter(|&cut| ahead_of_chaser.contains(cut))
+            .map(Coord::from_bit_index)
+            .filter(|&cut| cut != prey_at && cut != chaser_at)
+            .filter(|&cut| {
+                Self::reachable_without(room, prey_at, cut).contains(chaser_at.bit_index())
+            })
+            .collect();
+        escapes.sort_by_key(|&cut| self.dist(prey_at, cut));
+        escapes
+    }
+    // a role and a goal coord for each of my teammates, in the order given.
+    // The nearest chases. The others block escapes they can reach before the prey, or else flank
+    pub fn assign(&self, world: &World, team: &[Coord]) -> Vec<(Role, Coord)> {
+        let prey_at = self.prey_model.most_likely_at(world);
+        let mut by_closeness: Vec<usize> = (0..team.len()).collect();
+        by_closeness.sort_by_key(|&i| self.dist(team[i], prey_at));
+        let mut roles = vec![(Role::Chaser, prey_at); team.len()];
+        let chaser_at = match by_closeness.first() {
+            Some(&i) => team[i],
+            None => return roles,
+        };
+        let mut escapes = self.escapes(world, prey_at, chaser_at);
+        // where the prey would step to get away from the chaser, most likely first
+        let mut flanks: Vec<Coord> = Direction::iter_domain()
+            .filter_map(|dir| prey_at.stepped_in_room(&world.room, dir))
+            .collect();
+        flanks.sort_by_key(|&dest| std::cmp::Reverse(self.dist(chaser_at, dest)));
+        for &i in by_closeness.iter().skip(1) {
+            let blockable = escapes
+                .iter()
+                .enumerate()
+                .filter(|&(_, &cut)| self.dist(team[i], cut) < self.dist(prey_at, cut))
+                .min_by_key(|&(_, &cut)| self.dist(team[i], cut))
+                .map(|(index, _)| index);
+            roles[i] = if let Some(index) = blockable {
+                (Role::Blocker, escapes.remove(index))
+            } else if !flanks.is_empty() {
+                (Role::Flanker, flanks.remove(0))
+            } else {
+                (Role::Chaser, prey_at)
+            };
+        }
+        roles
+    }
+}
+impl Ai for TeamAi {
+    fn update(&mut self, world: &World, _rng: &mut Rng) -> Vel {
+        let team = [coord_of_player(world, self.my_color)];
+        self.planner.observe(world, &team);
+        if let Some(here) = self.steering.wants_plan(world, self.my_color) {
+            let (_, goal) = self.planner.assign(world, &team)[0];
+            let sp = &self.planner.sp;
+            let a = step_toward(sp, &world.room, here, goal);
+            let b = step_toward(sp, &world.room, a, goal);
+            self.steering.follow(world, self.my_color, here, [a, b]);
+        }
+        self.steering.vel(world, self.my_color)
+    }
+    fn i_was_moved(&mut self, world: &World) {
+        self.steering = Steering::new(world, self.my_color);
+    }
+}
+impl AiExt for TeamAi {
+    fn new(my_color: PlayerColor, world: &World, _rng: &mut Rng) -> Self {
+        Self {
+            my_color,
+            planner: TeamPlanner::new(my_color, world),
+            steering: Steering::new(world, my_color),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::game::{rules::Rules, Entities},
+    };
+
+    // a room without walls, teleporters or doors
+    fn open_world() -> World {
+        let room =
+            Room { wall_sets: DimMap::new([BitIndexSet::default(), BitIndexSet::default()]) };
+        let entities = Entities { players: Default::default(), teleporters: vec![] };
+        let rules = Rules { num_teleporters: 0, num_my_doors: 0, ..Rules::default() };
+        World { room, entities, doors: Default::default(), rules }
+    }
+    fn place(world: &mut World, color: PlayerColor, at: Coord, dir: Option<Direction>) {
+        let player = &mut world.entities.players[color];
+        player.pos = at.center_pos();
+        player.vel = Vel::default();
+        if let Some(dir) = dir {
+            player.vel[dir.dim()] = Some(dir.sign());
+        }
+    }
+
+    #[test]
+    fn predictions_converge_on_a_scripted_opponent() {
+        let color = PlayerColor::Blue;
+        // straight ahead, and round and round clockwise
+        for script in [&[Right][..], &[Right, Down, Left, Up][..]].iter() {
+            let mut world = open_world();
+            // too far to threaten them. They never flee
+            place(&mut world, color.predator(), Coord::from_bit_index(BitIndex(4 * 16)), None);
+            let mut at = Coord::from_bit_index(BitIndex(0));
+            place(&mut world, color, at, None);
+            let mut model = OpponentModel::new(color, &world);
+            // how likely the model found each of their steps, before they took it
+            let mut foreseen = vec![];
+            for &dir in script.iter().cycle().take(64) {
+                let next = at.stepped(dir);
+                foreseen.push(model.predict(&world, 1)[next.bit_index()]);
+                at = next;
+                place(&mut world, color, at, Some(dir));
+                model.observe(&world);
+            }
+            assert!(foreseen[0] < 0.5, "knew {:?} before watching them", script);
+            assert!(foreseen[63] > 0.9, "foresaw only {} of {:?}", foreseen[63], script);
+            // and where they went while I was not looking
+            let mut expected = at;
+            for &dir in script.iter().cycle().skip(64).take(3) {
+                expected = expected.stepped(dir);
+            }
+            for _ in 0..world.rules.move_speed * 3 {
+                model.unobserved();
+            }
+            assert_eq!(model.most_likely_at(&world), expected, "lost track of {:?}", script);
+        }
+    }
+
+    // a corridor along the top row, from (0,0) to (8,0), with the prey of `color` at (4,0)
+    fn corridor_planner(color: PlayerColor) -> (World, TeamPlanner) {
+        let mut world = open_world();
+        world.room = Room::walled();
+        for x in 0..8 {
+            world.room.open(Coord::from_cells(x, 0), Right);
+        }
+        place(&mut world, color.prey(), Coord::from_cells(4, 0), None);
+        let planner = TeamPlanner::new(color, &world);
+        (world, planner)
+    }
+
+    #[test]
+    fn escapes_are_beyond_the_prey() {
+        let (world, planner) = corridor_planner(PlayerColor::Black);
+        let [prey_at, chaser_at] = [Coord::from_cells(4, 0), Coord::from_cells(2, 0)];
+        let escapes = planner.escapes(&world, prey_at, chaser_at);
+        let expected: Vec<Coord> = (5..8).map(|x| Coord::from_cells(x, 0)).collect();
+        assert_eq!(escapes, expected, "nearest first. None between the prey and chaser, or behind");
+    }
+
+    #[test]
+    fn teammates_chase_block_and_flank() {
+        let (world, planner) = corridor_planner(PlayerColor::Black);
+        let prey_at = Coord::from_cells(4, 0);
+        let team = [Coord::from_cells(8, 0), Coord::from_cells(2, 0), Coord::from_cells(0, 0)];
+        let roles = planner.assign(&world, &team);
+        assert_eq!(
+            roles,
+            vec![
+                (Role::Blocker, Coord::from_cells(7, 0)),
+                (Role::Chaser, prey_at),
+                // behind the chaser, it reaches no escape before the prey
+                (Role::Flanker, Coord::from_cells(5, 0)),
+            ]
+        );
+        let alone = planner.assign(&world, &team[1..2]);
+        assert_eq!(alone, vec![(Role::Chaser, prey_at)]);
+    }
+}
@@ -0,0 +1,222 @@
+use {
+    crate::{
+        game::{
+            ai::Ai,
+            config::BotConfig,
+            net,
+            rendering::VIEW_SIZE,
+            room::{CELL_COUNTS, CELL_SIZE},
+            PlayerColor, World,
+        },
+        prelude::*,
+    },
+    std::{
+        fmt::Write as _,
+        io,
+        net::{SocketAddr, UdpSocket},
+        process::{Child, Command},
+    },
+};
+
+/*
+An AI whose decisions are made by a separate process, talking plain text over a local UDP socket.
+1. The bot sends any datagram to `BotConfig::listen_addr` to announce itself. Only the first to
+   announce itself is the bot. Datagrams from anywhere else are ignored.
+2. Every tick, the bot receives an observation: ASCII lines of whitespace-separated fields.
+    tick <tick>
+    cells <x_count> <y_count> <cell_width> <cell_height>
+    you <color> <x> <y>
+    player <color> <x> <y>        (one per VISIBLE peer)
+    teleporter <x> <y>            (one per teleporter)
+    walls <X|Y> <bit_index>...    (cells with a wall in this dim)
+    door <X|Y> <bit_index>        (one per door of mine. A wall only I can pass)
+3. The bot replies with `<tick> <vx> <vy>`, where each of vx, vy is one of -1, 0, 1.
+If no reply arrives for `BotConfig::timeout_ticks`, the player stands still.
+*/
+pub struct ExternalAi {
+    my_color: PlayerColor,
+    udp: UdpSocket, // nonblocking. bound.
+    bot_addr: Option<SocketAddr>,
+    timeout_ticks: u32,
+    tick: u32,
+    last_reply_tick: u32,
+    vel: Vel,
+    child: Option<Child>,
+    buf: Vec<u8>,
+}
+
+/////////////////////////////////
+fn write_color(s: &mut String, color: PlayerColor) {
+    write!(s, "{:?}", color).unwrap();
+}
+fn parse_vel_component(s: &str) -> Option<Option<Sign>> {
+    match s {
+        "-1" => Some(Some(Negative)),
+        "0" => Some(None),
+        "1" => Some(Some(Positive)),
+        _ => None,
+    }
+}
+impl ExternalAi {
+    const BUF_CAP: usize = 256;
+    pub fn new(my_color: PlayerColor, config: &BotConfig) -> io::Result<Self> {
+        let udp = net::bind_udp(config.listen_addr)?;
+        udp.set_nonblocking(true)?;
+        let child = match config.spawn.split_first() {
+            Some((program, args)) => {
+                // the bot is told where to find us as its last argument
+                Some(Command::new(program).args(args).arg(udp.local_addr()?.to_string()).spawn()?)
+            }
+            None => None,
+        };
+        Ok(Self {
+            my_color,
+            udp,
+            bot_addr: None,
+            timeout_ticks: config.timeout_ticks as u32,
+            tick: 0,
+            last_reply_tick: 0,
+            vel: Vel::default(),
+            child,
+            buf: vec![0; Self::BUF_CAP],
+        })
+    }
+    fn recv_replies(&mut self) {
+        while let Ok((n, addr)) = self.udp.recv_from(&mut self.buf) {
+            if *self.bot_addr.get_or_insert(addr) != addr {
+                continue; // not my bot. It may not steer me
+            }
+            let reply = std::str::from_utf8(&self.buf[..n]).ok().and_then(|s| {
+                let mut fields = s.split_whitespace();
+                let tick: u32 = fields.next()?.parse().ok()?;
+                let x = parse_vel_component(fields.next()?)?;
+                let y = parse_vel_component(fields.next()?)?;
+                Some((tick, Vel::new_xy(x, y)))
+            });
+            if let Some((tick, vel)) = reply {
+                if self.last_reply_tick <= tick && tick <= self.tick {
+                    self.last_reply_tick = tick;
+                    self.vel = vel;
+                }
+            }
+        }
+    }
+    fn observation(&self, world: &World) -> String {
+        let mut s = String::new();
+        let my_pos = world.entities.players[self.my_color].pos;
+        let pos_fields = |s: &mut String, pos: Pos| {
+            let [x, y]: [u16; 2] = pos.map(Into::<u16>::into).arr;
+            write!(s, " {} {}", x, y).unwrap();
+        };
+        writeln!(s, "tick {}", self.tick).unwrap();
+        writeln!(
+            s,
+            "cells {} {} {} {}",
+            CELL_COUNTS[X], CELL_COUNTS[Y], CELL_SIZE[X], CELL_SIZE[Y]
+        )
+        .unwrap();
+        s.push_str("you ");
+        write_color(&mut s, self.my_color);
+        pos_fields(&mut s, my_pos);
+        s.push('\n');
+        for color in ArrIter::new(self.my_color.predator_prey()) {
+            let pos = world.entities.players[color].pos;
+            if (pos - my_pos).distances_from_zero() < VIEW_SIZE {
+                s.push_str("player ");
+                write_color(&mut s, color);
+                pos_fields(&mut s, pos);
+                s.push('\n');
+            }
+        }
+        for &pos in world.entities.teleporters.iter() {
+            s.push_str("teleporter");
+            pos_fields(&mut s, pos);
+            s.push('\n');
+        }
+        for dim in Dim::iter_domain() {
+            write!(s, "walls {:?}", dim).unwrap();
+            for bi in world.room.wall_sets[dim].iter() {
+                write!(s, " {}", bi.0).unwrap();
+            }
+            s.push('\n');
+        }
+        for door in world.doors[self.my_color].iter() {
+            writeln!(s, "door {:?} {}", door.dim, door.coord.bit_index().0).unwrap();
+        }
+        s
+    }
+}
+impl Ai for ExternalAi {
+    fn i_was_moved(&mut self, _world: &World) {
+        // the bot will see its new position in the next observation
+    }
+    fn update(&mut self, world: &World, _rng: &mut Rng) -> Vel {
+        self.tick += 1;
+        self.recv_replies();
+        if let Some(bot_addr) = self.bot_addr {
+            let observation = self.observation(world);
+            // a bot that went away is handled by the timeout below
+            let _ = self.udp.send_to(observation.as_bytes(), bot_addr);
+        }
+        if self.tick - self.last_reply_tick > self.timeout_ticks {
+            // timed out! stand still
+            self.vel = Vel::default();
+        }
+        self.vel
+    }
+}
+impl Drop for ExternalAi {
+    fn drop(&mut self) {
+        if let Some(child) = &mut self.child {
+            let _ = child.kill();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::game::{room::Room, rules::Rules, Entities, PlayerArr, PlayerArrExt},
+        std::{net::Ipv4Addr, thread, time::Duration},
+    };
+
+    fn send(udp: &UdpSocket, to: SocketAddr, text: &str) {
+        udp.send_to(text.as_bytes(), to).unwrap();
+        thread::sleep(Duration::from_millis(20)); // for loopback to deliver it
+    }
+
+    #[test]
+    fn only_the_first_to_announce_itself_steers() {
+        let rules = Rules::default();
+        let (room, mut rng) = Room::new_seeded(3);
+        let entities = Entities::random(&mut rng, &rules);
+        let doors = PlayerArr::new_with(|_| room.random_new_my_doors(&mut rng, &rules));
+        let world = World { room, entities, doors, rules };
+        let config = BotConfig {
+            color: PlayerColor::Black,
+            listen_addr: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0),
+            timeout_ticks: 100,
+            spawn: vec![],
+        };
+        let mut ai = ExternalAi::new(config.color, &config).unwrap();
+        let ai_addr = ai.udp.local_addr().unwrap();
+        let bot = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let intruder = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        send(&bot, ai_addr, "hi");
+        send(&intruder, ai_addr, "hi");
+        ai.update(&world, &mut rng);
+        bot.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let mut buf = vec![0; 4096];
+        let n = bot.recv(&mut buf).unwrap();
+        let observation = std::str::from_utf8(&buf[..n]).unwrap();
+        let doors = observation.lines().filter(|line| line.starts_with("door ")).count();
+        assert_eq!(doors, world.rules.num_my_doors as usize);
+        send(&intruder, ai_addr, "1 1 1");
+        let vel = ai.update(&world, &mut rng);
+        assert!(matches!(vel.arr, [None, None]), "the intruder steered");
+        send(&bot, ai_addr, "2 -1 0");
+        let vel = ai.update(&world, &mut rng);
+        assert!(matches!(vel.arr, [Some(Negative), None]), "the bot did not steer");
+    }
+}
@@ -0,0 +1,164 @@
+use {
+    crate::{
+        game::{
+            net::{
+                reliable::{Event, PingKind},
+                MAX_CHAT_LEN,
+            },
+            GameState, Net, PlayerColor,
+        },
+        prelude::*,
+    },
+    gfx_2020::winit::event::{ElementState, VirtualKeyCode},
+};
+
+// drawn over the maze for a while: where someone pinged, or beside someone who chatted
+#[derive(Copy, Clone, Debug)]
+pub struct Marker {
+    pub anchor: MarkerAnchor,
+    pub from: Option<PlayerColor>,
+    pub ticks_left: u16,
+}
+#[derive(Copy, Clone, Debug)]
+pub enum MarkerAnchor {
+    At(Pos),
+    Beside(PlayerColor),
+}
+// drawn in a corner of the view for a while, in the color of its sender
+#[derive(Clone, Debug)]
+pub struct ChatLine {
+    pub from: Option<PlayerColor>,
+    pub text: String,
+    pub ticks_left: u16,
+}
+
+pub const MAX_MARKERS: u32 = 8;
+// shown at once. The last is my draft, while I type one
+pub const MAX_CHAT_LINES: u32 = 4;
+// enough for a sender's name, and the longest chat
+pub const MAX_LINE_CHARS: u32 = MAX_CHAT_LEN as u32 + 16;
+const PING_TICKS: u16 = 120;
+const SPEECH_TICKS: u16 = 180;
+const CHAT_LINE_TICKS: u16 = 600;
+
+/////////////////////////////////
+fn sender_name(from: Option<PlayerColor>) -> String {
+    match from {
+        Some(color) => format!("{:?}", color),
+        None => "A spectator".into(),
+    }
+}
+impl GameState {
+    // reaches everyone, me included. Clients' events go through the server, which may drop them
+    fn send_event(&mut self, event: Event) {
+        match &mut self.net {
+            Net::Server { server, .. } => {
+                server.broadcast(event.clone());
+                self.handle_net_event(event);
+            }
+            Net::Client(client) => client.send_event(event),
+            Net::Migrating(_) => {
+                let text = "(not sent. looking for the next host)".to_string();
+                println!("{}", text);
+                self.push_chat_line(None, text);
+            }
+        }
+    }
+    fn push_marker(&mut self, anchor: MarkerAnchor, from: Option<PlayerColor>, ticks_left: u16) {
+        if self.markers.len() == MAX_MARKERS as usize {
+            self.markers.pop_front();
+        }
+        self.markers.push_back(Marker { anchor, from, ticks_left });
+    }
+    pub(super) fn show_chat_or_ping(&mut self, event: Event) {
+        match event {
+            Event::Chat { from, text } => {
+                let text = format!("{}: {}", sender_name(from), text);
+                println!("{}", text);
+                if let Some(color) = from {
+                    self.push_marker(MarkerAnchor::Beside(color), from, SPEECH_TICKS);
+                }
+                self.push_chat_line(from, text);
+            }
+            Event::ChatDropped => {
+                let text = "(too fast! your last message was dropped)".to_string();
+                println!("{}", text);
+                self.push_chat_line(None, text);
+            }
+            Event::Ping { from, kind, pos } => {
+                println!("{} pings {:?}!", sender_name(from), kind);
+                self.push_marker(MarkerAnchor::At(pos), from, PING_TICKS);
+            }
+            Event::Captured { .. } | Event::DoorUsed { .. } | Event::DoorMoved { .. } => {}
+        }
+    }
+    fn push_chat_line(&mut self, from: Option<PlayerColor>, text: String) {
+        if self.chat_log.len() == MAX_CHAT_LINES as usize - 1 {
+            self.chat_log.pop_front();
+        }
+        self.chat_log.push_back(ChatLine { from, text, ticks_left: CHAT_LINE_TICKS });
+    }
+    pub(super) fn update_markers(&mut self) {
+        for marker in self.markers.iter_mut() {
+            marker.ticks_left = marker.ticks_left.saturating_sub(1);
+        }
+        self.markers.retain(|marker| marker.ticks_left > 0);
+        for line in self.chat_log.iter_mut() {
+            line.ticks_left = line.ticks_left.saturating_sub(1);
+        }
+        self.chat_log.retain(|line| line.ticks_left > 0);
+    }
+    // the chat lines to draw, oldest first, and then my draft
+    pub(super) fn chat_lines(&self) -> impl Iterator<Item = (Option<PlayerColor>, String)> + '_ {
+        let log = self.chat_log.iter().map(|line| (line.from, line.text.clone()));
+        let draft =
+            self.chat_draft.as_ref().map(|draft| (self.controlling, format!("> {}_", draft)));
+        log.chain(draft)
+    }
+    pub(super) fn ping(&mut self, kind: PingKind) {
+        let event = Event::Ping { from: self.controlling, kind, pos: self.camera_pos() };
+        self.send_event(event);
+    }
+    pub(super) fn start_chat(&mut self) {
+        println!("Chat: type a message, then press {:?} to send it", self.input_config.chat);
+        self.chat_draft = Some(String::new());
+    }
+    pub(super) fn handle_chat_char(&mut self, c: char) {
+        if let Some(draft) = &mut self.chat_draft {
+            if !c.is_control() && draft.len() + c.len_utf8() <= MAX_CHAT_LEN {
+                draft.push(c);
+            }
+        }
+    }
+    // while the chat prompt is open, keys edit the draft instead of playing
+    pub(super) fn handle_chat_key(&mut self, vkc: VirtualKeyCode, state: ElementState) {
+        let input = &self.input_config;
+        if state == ElementState::Released {
+            // movement keys are still released, or I would keep moving after chatting
+            let move_keys =
+                [(input.up, Up), (input.down, Down), (input.left, Left), (input.right, Right)];
+            if let Some(&(_, dir)) = move_keys.iter().find(|(key, _)| *key == vkc) {
+                self.update_move_key(dir, state);
+            }
+            return;
+        }
+        match vkc {
+            x if x == input.exit => {
+                self.chat_draft = None;
+                println!("(chat cancelled)");
+            }
+            x if x == input.chat => {
+                let text = self.chat_draft.take().unwrap_or_default();
+                if !text.is_empty() {
+                    self.send_event(Event::Chat { from: self.controlling, text });
+                }
+            }
+            VirtualKeyCode::Back => {
+                if let Some(draft) = &mut self.chat_draft {
+                    draft.pop();
+                }
+            }
+            _ => {}
+        }
+    }
+}
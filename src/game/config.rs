@@ -1,7 +1,15 @@
 use {
-    crate::{game::PlayerColor, prelude::*},
+    crate::{
+        game::{discovery::DISCOVERY_PORT, net::reliable::PingKind, PlayerColor},
+        prelude::*,
+    },
     gfx_2020::winit::event::VirtualKeyCode,
-    std::{fs::File, io::Write, net::SocketAddrV4, path::Path},
+    std::{
+        fs::File,
+        io::Write,
+        net::{Ipv4Addr, SocketAddr},
+        path::Path,
+    },
 };
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -11,6 +19,17 @@ pub struct InputConfig {
     pub down: VirtualKeyCode,
     pub left: VirtualKeyCode,
     pub right: VirtualKeyCode,
+    // spectators: cycle the camera between following each player and roaming freely
+    #[serde(default = "InputConfig::default_switch_camera")]
+    pub switch_camera: VirtualKeyCode,
+    // opens the chat prompt, and sends what was typed
+    #[serde(default = "InputConfig::default_chat")]
+    pub chat: VirtualKeyCode,
+    #[serde(default = "InputConfig::default_pings")]
+    pub pings: Vec<(VirtualKeyCode, PingKind)>,
+    // shows (and periodically prints) round trip time, loss, bandwidth and snapshot age
+    #[serde(default = "InputConfig::default_net_stats")]
+    pub net_stats: VirtualKeyCode,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -19,22 +38,176 @@ pub struct Config {
     pub if_client: IfClient,
     pub if_server: IfServer,
     pub input: InputConfig,
+    // simulate a bad network connection. Overridden by the env var `NetSimConfig::ENV_VAR`
+    #[serde(default)]
+    pub net_sim: Option<NetSimConfig>,
+    // if Some, this process runs a lobby (without a window) instead of playing
+    #[serde(default)]
+    pub if_lobby: Option<IfLobby>,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct IfClient {
-    pub server_addr: SocketAddrV4,
+    // "host:port", where host is a name, an IPv4 address, or a bracketed IPv6 address
+    pub server_addr: String,
     pub preferred_color: PlayerColor,
+    // other players are drawn where they were this many ticks ago, smoothing uneven arrivals
+    #[serde(default = "IfClient::default_interpolation_delay_ticks")]
+    pub interpolation_delay_ticks: u16,
+    // if Some, `server_addr` is ignored. The lobby tells us which game to join
+    #[serde(default)]
+    pub via_lobby: Option<ViaLobby>,
+    // if Some (and `via_lobby` is None), `server_addr` is ignored. I pick from servers on the LAN
+    #[serde(default)]
+    pub discover: Option<Discover>,
+    // watch without a player of my own
+    #[serde(default)]
+    pub spectate: bool,
+    // must match the server's
+    #[serde(default)]
+    pub password: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Discover {
+    // queries go here. Only a broadcast address reaches every server listening on a host. A
+    // unicast one (e.g. loopback) reaches just one of them, as they share the discovery port
+    #[serde(default = "Discover::default_broadcast_addr")]
+    pub broadcast_addr: SocketAddr,
+    // how long to wait for servers to answer
+    #[serde(default = "Discover::default_wait_millis")]
+    pub wait_millis: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ViaLobby {
+    // "host:port", like `IfClient::server_addr`
+    pub lobby_addr: String,
+    pub join: LobbyJoin,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum LobbyJoin {
+    // any listed game with a free color, else a new game with the lobby's default settings
+    AnyOpen,
+    Game(u32),
+    Create(GameSettings),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GameSettings {
+    pub room_seed: Option<u64>,
+    pub ai_enabled: Vec<PlayerColor>,
+    #[serde(default)]
+    pub ai_kind: AiKind,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct IfLobby {
+    pub lobby_addr: SocketAddr,
+    // games are hosted on ports chosen by the OS, at the lobby's IP
+    pub max_games: u32,
+    pub default_settings: GameSettings,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct IfServer {
-    pub server_addr: SocketAddrV4,
+    // binding to "[::]:port" accepts both IPv6 and IPv4 clients
+    pub server_addr: SocketAddr,
     pub player_color: PlayerColor,
     pub room_seed: Option<u64>,
     pub ai_enabled: Vec<PlayerColor>,
+    // plays the colors in `ai_enabled`
+    #[serde(default)]
+    pub ai_kind: AiKind,
+    #[serde(default)]
+    pub bots: Vec<BotConfig>,
+    // if Some, only clients with the same password can join
+    #[serde(default)]
+    pub password: Option<String>,
+    // shown to clients discovering servers on the LAN
+    #[serde(default = "IfServer::default_name")]
+    pub name: String,
+    // if Some, LAN discovery queries are answered here. Conventionally port 8999 of 0.0.0.0
+    #[serde(default)]
+    pub discovery_addr: Option<SocketAddr>,
+    // if Some, clients that make this many impossible moves are kicked
+    #[serde(default)]
+    pub kick_after_violations: Option<u32>,
+}
+
+// the built-in AIs
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AiKind {
+    // looks a few moves ahead of everyone, wherever they are
+    MiniMax,
+    // sees only what is near, and predicts where the others went from how they tend to move
+    Sink,
+    // heads for the cell nearest its prey and farthest from its predator
+    PathLengths,
+    // plays a role given by a planner for its whole color. Shares what its teammates see
+    Team,
+}
+
+// applied to datagrams in both directions
+#[derive(Serialize, Deserialize, Clone)]
+pub struct NetSimConfig {
+    pub latency_ms: u32,
+    pub jitter_ms: u32,
+    // probabilities in 0.0..=1.0
+    pub loss: f32,
+    pub duplication: f32,
+    pub reorder: f32,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct BotConfig {
+    pub color: PlayerColor,
+    pub listen_addr: SocketAddr,
+    pub timeout_ticks: u16,
+    // if nonempty, the program (and args) to spawn. it is passed `listen_addr` as a final arg
+    #[serde(default)]
+    pub spawn: Vec<String>,
+}
+
+impl InputConfig {
+    fn default_switch_camera() -> VirtualKeyCode {
+        VirtualKeyCode::Tab
+    }
+    fn default_chat() -> VirtualKeyCode {
+        VirtualKeyCode::Return
+    }
+    fn default_pings() -> Vec<(VirtualKeyCode, PingKind)> {
+        use {PingKind as Pk, VirtualKeyCode as Vkc};
+        vec![(Vkc::Key1, Pk::LookHere), (Vkc::Key2, Pk::Danger), (Vkc::Key3, Pk::NeedHelp)]
+    }
+    fn default_net_stats() -> VirtualKeyCode {
+        VirtualKeyCode::F3
+    }
+}
+impl Discover {
+    fn default_broadcast_addr() -> SocketAddr {
+        SocketAddr::new(Ipv4Addr::BROADCAST.into(), DISCOVERY_PORT)
+    }
+    fn default_wait_millis() -> u64 {
+        1000
+    }
+}
+impl IfClient {
+    fn default_interpolation_delay_ticks() -> u16 {
+        6
+    }
+}
+impl Default for AiKind {
+    fn default() -> Self {
+        Self::MiniMax
+    }
+}
+impl IfServer {
+    pub fn default_name() -> String {
+        "honeydew".into()
+    }
+}
 impl Config {
     pub fn try_load_from(path: &Path) -> Option<Self> {
         File::open(path).ok().and_then(|f| ron::de::from_reader(f).ok())
@@ -48,16 +221,30 @@ impl Config {
 }
 impl Default for Config {
     fn default() -> Self {
-        let server_addr = SocketAddrV4::new(std::net::Ipv4Addr::LOCALHOST, 8000);
+        let server_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 8000);
         use {PlayerColor as Pc, VirtualKeyCode as Vkc};
         Self {
             server_mode: true,
-            if_client: IfClient { preferred_color: PlayerColor::Black, server_addr },
+            if_client: IfClient {
+                preferred_color: PlayerColor::Black,
+                server_addr: server_addr.to_string(),
+                interpolation_delay_ticks: IfClient::default_interpolation_delay_ticks(),
+                via_lobby: None,
+                discover: None,
+                spectate: false,
+                password: None,
+            },
             if_server: IfServer {
                 room_seed: None,
                 player_color: PlayerColor::Black,
                 server_addr,
                 ai_enabled: vec![Pc::Black, Pc::Blue, Pc::Orange],
+                ai_kind: AiKind::default(),
+                bots: vec![],
+                password: None,
+                name: IfServer::default_name(),
+                discovery_addr: None,
+                kick_after_violations: None,
             },
             input: InputConfig {
                 up: Vkc::W,
@@ -65,7 +252,13 @@ impl Default for Config {
                 left: Vkc::A,
                 right: Vkc::D,
                 exit: Vkc::Escape,
+                switch_camera: InputConfig::default_switch_camera(),
+                chat: InputConfig::default_chat(),
+                pings: InputConfig::default_pings(),
+                net_stats: InputConfig::default_net_stats(),
             },
+            net_sim: None,
+            if_lobby: None,
         }
     }
 }
@@ -0,0 +1,266 @@
+use {
+    crate::{
+        game::{
+            config::Discover,
+            net::{self, bincode_config, JoinError, NetError},
+            rules::Rules,
+            PlayerColor, NUM_PLAYERS,
+        },
+        prelude::*,
+    },
+    bincode::Options,
+    std::{
+        collections::VecDeque,
+        io::{self, BufRead},
+        net::{IpAddr, SocketAddr, UdpSocket},
+        time::Instant,
+    },
+};
+
+/*
+Finding servers on the local network, without knowing their addresses.
+1. Servers with a `discovery_addr` listen there (conventionally on `DISCOVERY_PORT`) for queries.
+2. A client broadcasts a `Query`, and collects the `Listing`s that come back for a moment.
+   Servers on one host share the port. Each gets a copy of broadcasts, but the OS gives unicast
+   queries to just one of them, so those find only one server per host.
+3. The client prints the listings, and asks which server to join.
+*/
+#[derive(Debug, Serialize, Deserialize)]
+enum DiscoveryMsg {
+    Query { magic: u32 },
+    Listing { magic: u32, listing: ServerListing },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerListing {
+    pub name: String,
+    // of the game itself. Its IP is the one the listing came from
+    pub game_port: u16,
+    pub protocol_version: u16,
+    pub ruleset_hash: u64,
+    pub num_players: u8,
+    pub free_colors: Vec<PlayerColor>,
+    pub password_required: bool,
+    // what it plays by. Clients play by these once they join
+    pub rules: Rules,
+}
+
+// answers queries on behalf of a server
+pub struct DiscoveryResponder {
+    udp: UdpSocket, // nonblocking. bound
+    buf: Vec<u8>,
+    // whom I answered recently, oldest first. Listings are larger than queries, so replying to
+    // every query would let spoofed ones flood their victim
+    replied: VecDeque<(IpAddr, Instant)>,
+}
+
+pub const DISCOVERY_PORT: u16 = 8999;
+// tells discovery messages apart from stray datagrams
+const MAGIC: u32 = u32::from_be_bytes(*b"hdew");
+const BUF_CAP: usize = 1024;
+const QUERY_RESEND: Duration = Duration::from_millis(250);
+// each source is answered at most this often. Under `QUERY_RESEND`, so every resend of an
+// honest client is answered
+const MIN_REPLY_INTERVAL: Duration = Duration::from_millis(200);
+// sources answered within `MIN_REPLY_INTERVAL`, beyond which queries go unanswered
+const MAX_RECENTLY_REPLIED: usize = 32;
+
+/////////////////////////////////
+// several servers on one host may listen on the same discovery port. All of them hear broadcasts
+fn bind_shared(addr: SocketAddr) -> io::Result<UdpSocket> {
+    use socket2::{Domain, Socket, Type};
+    let domain = if addr.is_ipv4() { Domain::ipv4() } else { Domain::ipv6() };
+    let socket = Socket::new(domain, Type::dgram(), None)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    Ok(socket.into_udp_socket())
+}
+impl DiscoveryResponder {
+    pub fn bind(addr: SocketAddr) -> io::Result<Self> {
+        let udp = bind_shared(addr)?;
+        udp.set_nonblocking(true)?;
+        Ok(Self { udp, buf: vec![0; BUF_CAP], replied: Default::default() })
+    }
+    // answers the queries that arrived since the last call, unless their sources were answered
+    // too recently, or too many sources were
+    pub fn respond(&mut self, listing: impl Fn() -> ServerListing) {
+        while let Ok((n, addr)) = self.udp.recv_from(&mut self.buf) {
+            if let Ok(DiscoveryMsg::Query { magic: MAGIC }) =
+                bincode_config().deserialize(&self.buf[..n])
+            {
+                let now = Instant::now();
+                while let Some(&(_, at)) = self.replied.front() {
+                    if now.duration_since(at) < MIN_REPLY_INTERVAL {
+                        break;
+                    }
+                    self.replied.pop_front();
+                }
+                if self.replied.len() >= MAX_RECENTLY_REPLIED
+                    || self.replied.iter().any(|&(ip, _)| ip == addr.ip())
+                {
+                    continue;
+                }
+                self.replied.push_back((addr.ip(), now));
+                let reply = DiscoveryMsg::Listing { magic: MAGIC, listing: listing() };
+                let _ = self.udp.send_to(&bincode_config().serialize(&reply).unwrap(), addr);
+            }
+        }
+    }
+}
+impl ServerListing {
+    fn is_joinable(&self) -> bool {
+        self.protocol_version == net::PROTOCOL_VERSION
+            && self.ruleset_hash == net::ruleset_hash()
+            && self.rules.is_valid()
+            && !self.free_colors.is_empty()
+    }
+}
+// asks the user which of the servers that answered a broadcast to join
+pub fn find_server(discover: &Discover) -> Result<SocketAddr, JoinError> {
+    let listings = discover_listings(discover)?;
+    if listings.is_empty() {
+        return Err(JoinError::NothingDiscovered);
+    }
+    println!("Discovered {} server(s):", listings.len());
+    for (i, (addr, l)) in listings.iter().enumerate() {
+        println!(
+            "  #{} {:?} at {:?} with {}/{} players, free colors {:?}{}{}",
+            i,
+            l.name,
+            addr,
+            l.num_players,
+            NUM_PLAYERS,
+            l.free_colors,
+            if l.password_required { ", needs a password" } else { "" },
+            if l.is_joinable() { "" } else { ", CANNOT JOIN" },
+        );
+        println!(
+            "     {} teleporters, {} doors each, speed {}",
+            l.rules.num_teleporters, l.rules.num_my_doors, l.rules.move_speed
+        );
+    }
+    let first_joinable = listings.iter().position(|(_, l)| l.is_joinable());
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        println!("Which server? (empty for the first joinable)");
+        let choice = match lines.next() {
+            None => first_joinable, // no more input. choose for them
+            Some(Ok(line)) if line.trim().is_empty() => first_joinable,
+            Some(Ok(line)) => line.trim().trim_start_matches('#').parse().ok(),
+            Some(Err(_)) => None,
+        };
+        match choice.and_then(|i| listings.get(i)) {
+            Some((addr, _)) => return Ok(*addr),
+            None if first_joinable.is_none() => return Err(JoinError::NothingDiscovered),
+            None => {}
+        }
+    }
+}
+// every listing that arrives within the wait, with the address of its game
+fn discover_listings(discover: &Discover) -> Result<Vec<(SocketAddr, ServerListing)>, NetError> {
+    let addr = net::unspecified_like(discover.broadcast_addr);
+    let udp = UdpSocket::bind(addr).map_err(|cause| NetError::Bind { addr, cause })?;
+    udp.set_broadcast(true)?;
+    udp.set_read_timeout(Some(QUERY_RESEND))?;
+    let query = bincode_config().serialize(&DiscoveryMsg::Query { magic: MAGIC }).unwrap();
+    let deadline = Instant::now() + Duration::from_millis(discover.wait_millis);
+    let mut next_query = Instant::now();
+    let mut listings: Vec<(SocketAddr, ServerListing)> = vec![];
+    let mut buf = vec![0; BUF_CAP];
+    while Instant::now() < deadline {
+        // re-send queries periodically, in case they (or the replies) are lost
+        if next_query <= Instant::now() {
+            let _ = udp.send_to(&query, discover.broadcast_addr);
+            next_query += QUERY_RESEND;
+        }
+        if let Ok((n, from)) = udp.recv_from(&mut buf) {
+            if let Ok(DiscoveryMsg::Listing { magic: MAGIC, listing }) =
+                bincode_config().deserialize(&buf[..n])
+            {
+                let game_addr = SocketAddr::new(from.ip(), listing.game_port);
+                if !listings.iter().any(|(addr, _)| *addr == game_addr) {
+                    listings.push((game_addr, listing));
+                }
+            }
+        }
+    }
+    Ok(listings)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        std::{net::Ipv4Addr, thread},
+    };
+
+    fn listing() -> ServerListing {
+        ServerListing {
+            name: "test".into(),
+            game_port: 4321,
+            protocol_version: net::PROTOCOL_VERSION,
+            ruleset_hash: net::ruleset_hash(),
+            num_players: 1,
+            free_colors: vec![PlayerColor::Black],
+            password_required: false,
+            rules: Rules::default(),
+        }
+    }
+    fn bind_responder() -> DiscoveryResponder {
+        DiscoveryResponder::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0)).unwrap()
+    }
+    // how many listings arrive before the socket's read timeout
+    fn count_listings(udp: &UdpSocket) -> usize {
+        let mut buf = vec![0; BUF_CAP];
+        let mut count = 0;
+        while let Ok(n) = udp.recv(&mut buf) {
+            if let Ok(DiscoveryMsg::Listing { magic: MAGIC, .. }) =
+                bincode_config().deserialize(&buf[..n])
+            {
+                count += 1;
+            }
+        }
+        count
+    }
+
+    #[test]
+    fn advertised_servers_are_discovered() {
+        let mut responder = bind_responder();
+        let responder_addr = responder.udp.local_addr().unwrap();
+        thread::spawn(move || loop {
+            responder.respond(listing);
+            thread::sleep(Duration::from_millis(10));
+        });
+        let discover = Discover { broadcast_addr: responder_addr, wait_millis: 600 };
+        let listings = discover_listings(&discover).unwrap();
+        assert_eq!(listings.len(), 1, "resent queries must not list a server twice");
+        let (addr, l) = &listings[0];
+        assert_eq!(*addr, SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 4321));
+        assert_eq!(l.name, "test");
+        assert!(l.is_joinable());
+    }
+
+    #[test]
+    fn sources_are_answered_at_most_once_per_interval() {
+        let mut responder = bind_responder();
+        let udp = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        udp.connect(responder.udp.local_addr().unwrap()).unwrap();
+        udp.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        let query = bincode_config().serialize(&DiscoveryMsg::Query { magic: MAGIC }).unwrap();
+        let flood = |responder: &mut DiscoveryResponder| {
+            for _ in 0..5 {
+                udp.send(&query).unwrap();
+            }
+            thread::sleep(Duration::from_millis(20)); // for loopback to deliver them
+            responder.respond(listing);
+        };
+        flood(&mut responder);
+        assert_eq!(count_listings(&udp), 1);
+        flood(&mut responder);
+        assert_eq!(count_listings(&udp), 0, "answered again within the interval");
+        thread::sleep(MIN_REPLY_INTERVAL);
+        flood(&mut responder);
+        assert_eq!(count_listings(&udp), 1);
+    }
+}
@@ -0,0 +1,432 @@
+use {
+    crate::{
+        game::{
+            config::{AiKind, GameSettings, IfLobby, IfServer, LobbyJoin, NetSimConfig, ViaLobby},
+            net::{self, bincode_config, JoinError, NetError, RejectReason, JOIN_PATIENCE},
+            rules::Rules,
+            HeadlessGame, PlayerColor, NUM_PLAYERS,
+        },
+        prelude::*,
+    },
+    bincode::Options,
+    std::{
+        collections::HashMap,
+        net::{IpAddr, SocketAddr, UdpSocket},
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering as AtomicOrdering},
+            mpsc, Arc,
+        },
+        time::Instant,
+    },
+};
+
+/*
+A lobby hosts many games at once, each a `HeadlessGame` on its own thread and its own port.
+1. A client asks the lobby for the list of games, which it prints.
+2. The client asks to join (or create) a game, and the lobby replies with that game's address.
+3. From then on, the client talks to the game's server directly, as if there were no lobby.
+*/
+#[derive(Debug, Serialize, Deserialize)]
+enum LobbyMsg {
+    CtsList,
+    StcList { games: Vec<GameListing> },
+    CtsJoin { join: LobbyJoin },
+    StcGoTo { game_addr: SocketAddr },
+    StcReject { reason: RejectReason },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct GameListing {
+    id: u32,
+    room_seed: u64,
+    ai_enabled: Vec<PlayerColor>,
+    num_clients: u8,
+}
+
+struct LobbyGame {
+    id: u32,
+    addr: SocketAddr,
+    room_seed: u64,
+    settings: GameSettings,
+    num_clients: Arc<AtomicUsize>, // written by the game's thread
+    finished: Arc<AtomicBool>,     // set by the game's thread as it returns
+}
+
+struct Lobby {
+    udp: UdpSocket, // bound. blocking
+    ip: IpAddr,
+    max_games: u32,
+    default_settings: GameSettings,
+    rules: Rules, // of every game
+    net_sim: Option<NetSimConfig>,
+    games: Vec<LobbyGame>,
+    // games close once they have had no clients for this many ticks
+    empty_game_ticks: u32,
+    next_id: u32, // IDs are never reused, so that clients never join the wrong game
+    // creating a game is not idempotent, but clients re-send requests. One game per client
+    created_by: HashMap<SocketAddr, u32>,
+    buf: Vec<u8>,
+}
+
+// headless games are not driven by a window's main loop. They tick at this rate
+const TICK_DURATION: Duration = Duration::from_micros(1_000_000 / 60);
+// games stop once they have had no clients for this long. Their slots become free.
+// Clients that vanish without a word are dropped by the game's server once they are silent
+const EMPTY_GAME_TICKS: u32 = 60 * 60;
+const BUF_CAP: usize = 2048;
+// clients re-send requests this often, in case they (or the replies) are lost
+const REQUEST_RESEND: Duration = Duration::from_millis(500);
+
+/////////////////////////////////
+impl LobbyGame {
+    fn num_clients(&self) -> usize {
+        self.num_clients.load(AtomicOrdering::Relaxed)
+    }
+    fn is_open(&self) -> bool {
+        self.num_clients() < NUM_PLAYERS as usize
+    }
+    fn is_finished(&self) -> bool {
+        self.finished.load(AtomicOrdering::Relaxed)
+    }
+    // None if the game could not be started
+    fn spawn(
+        id: u32,
+        ip: IpAddr,
+        settings: GameSettings,
+        rules: Rules,
+        net_sim: Option<NetSimConfig>,
+        empty_game_ticks: u32,
+    ) -> Option<Self> {
+        let config = IfServer {
+            server_addr: SocketAddr::new(ip, 0),
+            player_color: PlayerColor::Black, // unused. the headless server controls no player
+            room_seed: settings.room_seed,
+            ai_enabled: settings.ai_enabled.clone(),
+            ai_kind: settings.ai_kind,
+            bots: vec![],
+            password: None,
+            name: IfServer::default_name(),
+            discovery_addr: None, // clients find these games through the lobby
+            kick_after_violations: None,
+        };
+        let num_clients = Arc::new(AtomicUsize::new(0));
+        let finished = Arc::new(AtomicBool::new(false));
+        let (num_clients2, finished2) = (num_clients.clone(), finished.clone());
+        let (sender, receiver) = mpsc::channel();
+        // AIs are not Send, so the game is created on its own thread
+        std::thread::spawn(move || {
+            let mut game = match HeadlessGame::new(&config, &rules, net_sim.as_ref()) {
+                Ok(game) => game,
+                Err(e) => return println!("Failed to start a game: {}", e),
+            };
+            let _ = sender.send((game.server.local_addr(), game.server.room_seed()));
+            let mut next_tick = Instant::now();
+            let mut empty_ticks = 0;
+            while empty_ticks < empty_game_ticks {
+                if let Err(e) = game.update() {
+                    println!("Stopped game {} at {:?}: {}", id, game.server.local_addr(), e);
+                    break;
+                }
+                let num_clients = game.server.num_clients();
+                num_clients2.store(num_clients, AtomicOrdering::Relaxed);
+                empty_ticks = if num_clients == 0 { empty_ticks + 1 } else { 0 };
+                next_tick += TICK_DURATION;
+                std::thread::sleep(next_tick.saturating_duration_since(Instant::now()));
+            }
+            // never listed as open again
+            num_clients2.store(NUM_PLAYERS as usize, AtomicOrdering::Relaxed);
+            finished2.store(true, AtomicOrdering::Relaxed);
+        });
+        let (addr, room_seed) = receiver.recv().ok()?;
+        Some(Self { id, addr, room_seed, settings, num_clients, finished })
+    }
+}
+impl Lobby {
+    fn new(
+        config: &IfLobby,
+        rules: &Rules,
+        net_sim: Option<&NetSimConfig>,
+    ) -> Result<Self, NetError> {
+        let addr = config.lobby_addr;
+        let udp = net::bind_udp(addr).map_err(|cause| NetError::Bind { addr, cause })?;
+        Ok(Self {
+            udp,
+            ip: addr.ip(),
+            max_games: config.max_games,
+            default_settings: config.default_settings.clone(),
+            rules: rules.clone(),
+            net_sim: net_sim.cloned(),
+            games: vec![],
+            empty_game_ticks: EMPTY_GAME_TICKS,
+            next_id: 0,
+            created_by: Default::default(),
+            buf: Vec::with_capacity(BUF_CAP),
+        })
+    }
+    // answers clients forever
+    fn serve(&mut self) {
+        let mut recv_buf = vec![0; BUF_CAP];
+        loop {
+            let (n, client_addr) = match self.udp.recv_from(&mut recv_buf) {
+                Ok(x) => x,
+                Err(_) => continue,
+            };
+            self.reap_finished();
+            let reply = match bincode_config().deserialize(&recv_buf[..n]) {
+                Ok(LobbyMsg::CtsList) => LobbyMsg::StcList { games: self.listing() },
+                Ok(LobbyMsg::CtsJoin { join }) => match self.join(join, client_addr) {
+                    Ok(game_addr) => LobbyMsg::StcGoTo { game_addr },
+                    Err(reason) => LobbyMsg::StcReject { reason },
+                },
+                _ => continue,
+            };
+            self.send_to(&reply, client_addr);
+        }
+    }
+    fn send_to(&mut self, msg: &LobbyMsg, addr: SocketAddr) {
+        bincode_config().serialize_into(&mut self.buf, msg).unwrap();
+        let _ = self.udp.send_to(&self.buf, addr);
+        self.buf.clear();
+    }
+    // forgets games whose threads have returned, freeing their slots
+    fn reap_finished(&mut self) {
+        let Self { games, created_by, .. } = self;
+        games.retain(|game| {
+            let finished = game.is_finished();
+            if finished {
+                println!("Lobby closed game {} at {:?}", game.id, game.addr);
+                created_by.retain(|_, id| *id != game.id);
+            }
+            !finished
+        });
+    }
+    fn listing(&self) -> Vec<GameListing> {
+        self.games
+            .iter()
+            .map(|game| GameListing {
+                id: game.id,
+                room_seed: game.room_seed,
+                ai_enabled: game.settings.ai_enabled.clone(),
+                num_clients: game.num_clients() as u8,
+            })
+            .collect()
+    }
+    fn create_game(&mut self, settings: GameSettings) -> Result<u32, RejectReason> {
+        if self.games.len() as u32 >= self.max_games {
+            return Err(RejectReason::ServerFull);
+        }
+        let id = self.next_id;
+        let (rules, net_sim) = (self.rules.clone(), self.net_sim.clone());
+        let game = LobbyGame::spawn(id, self.ip, settings, rules, net_sim, self.empty_game_ticks)
+            .ok_or(RejectReason::ServerFull)?;
+        self.next_id += 1;
+        println!("Lobby started game {} at {:?} with seed {}", id, game.addr, game.room_seed);
+        self.games.push(game);
+        Ok(id)
+    }
+    fn join(
+        &mut self,
+        join: LobbyJoin,
+        client_addr: SocketAddr,
+    ) -> Result<SocketAddr, RejectReason> {
+        let id = match join {
+            LobbyJoin::Game(id) => id,
+            LobbyJoin::AnyOpen => match self.games.iter().find(|game| game.is_open()) {
+                Some(game) => game.id,
+                None => self.create_game(self.default_settings.clone())?,
+            },
+            LobbyJoin::Create(settings) => match self.created_by.get(&client_addr) {
+                Some(&id) => id,
+                None => {
+                    let id = self.create_game(settings)?;
+                    self.created_by.insert(client_addr, id);
+                    id
+                }
+            },
+        };
+        match self.games.iter().find(|game| game.id == id) {
+            Some(game) if game.is_open() => Ok(game.addr),
+            _ => Err(RejectReason::NoSuchGame),
+        }
+    }
+}
+// runs until the process is killed, unless the lobby cannot bind its addr
+pub fn run(config: &IfLobby, rules: &Rules, net_sim: Option<&NetSimConfig>) {
+    match Lobby::new(config, rules, net_sim) {
+        Ok(mut lobby) => {
+            println!("Lobby listening at {:?}", config.lobby_addr);
+            lobby.serve();
+        }
+        Err(e) => println!("{}", e),
+    }
+}
+// sends `msg` until the lobby replies with a message that `is_reply`, or `deadline` passes
+fn request(
+    udp: &UdpSocket,
+    buf: &mut [u8],
+    msg: &LobbyMsg,
+    is_reply: fn(&LobbyMsg) -> bool,
+    deadline: Instant,
+) -> Result<LobbyMsg, NetError> {
+    // the lobby may not be up yet. If it never comes up, this is why
+    let mut refused = None;
+    while Instant::now() < deadline {
+        let received =
+            udp.send(&bincode_config().serialize(msg).unwrap()).and_then(|_| udp.recv(buf));
+        match received {
+            Ok(n) => match bincode_config().deserialize(&buf[..n]) {
+                Ok(reply) if is_reply(&reply) => return Ok(reply),
+                _ => {} // maybe a late reply to an earlier request
+            },
+            Err(e) if net::is_would_block(&e) => {}
+            Err(e) if net::is_peer_error(&e) => {
+                refused = Some(e);
+                std::thread::sleep(REQUEST_RESEND);
+            }
+            Err(e) => return Err(NetError::Io(e)),
+        }
+    }
+    Err(refused.map(NetError::ServerUnreachable).unwrap_or(NetError::ServerSilent))
+}
+// asks the lobby which game to join, printing the games it lists along the way
+pub fn find_game(via_lobby: &ViaLobby) -> Result<SocketAddr, JoinError> {
+    find_game_within(via_lobby, JOIN_PATIENCE)
+}
+// gives up if the lobby does not answer within `patience`
+fn find_game_within(via_lobby: &ViaLobby, patience: Duration) -> Result<SocketAddr, JoinError> {
+    let deadline = Instant::now() + patience;
+    let lobby_addr = net::resolve(&via_lobby.lobby_addr)?;
+    let addr = net::unspecified_like(lobby_addr);
+    let udp = UdpSocket::bind(addr).map_err(|cause| NetError::Bind { addr, cause })?;
+    udp.connect(lobby_addr).map_err(|cause| NetError::Connect { addr: lobby_addr, cause })?;
+    udp.set_read_timeout(Some(REQUEST_RESEND)).map_err(NetError::Io)?;
+    let mut buf = vec![0; BUF_CAP];
+    let is_list = |msg: &LobbyMsg| matches!(msg, LobbyMsg::StcList { .. });
+    if let LobbyMsg::StcList { games } =
+        request(&udp, &mut buf, &LobbyMsg::CtsList, is_list, deadline)?
+    {
+        println!("Lobby at {:?} lists {} game(s):", lobby_addr, games.len());
+        for g in games.iter() {
+            println!(
+                "  #{} seed {} with {}/{} clients, AIs {:?}",
+                g.id, g.room_seed, g.num_clients, NUM_PLAYERS, g.ai_enabled
+            );
+        }
+    }
+    let is_join_reply =
+        |msg: &LobbyMsg| matches!(msg, LobbyMsg::StcGoTo { .. } | LobbyMsg::StcReject { .. });
+    let join = LobbyMsg::CtsJoin { join: via_lobby.join.clone() };
+    match request(&udp, &mut buf, &join, is_join_reply, deadline)? {
+        LobbyMsg::StcGoTo { mut game_addr } => {
+            if game_addr.ip().is_unspecified() {
+                // the lobby listens on every interface. The game is where I reached the lobby
+                game_addr.set_ip(lobby_addr.ip());
+            }
+            Ok(game_addr)
+        }
+        LobbyMsg::StcReject { reason } => Err(reason.into()),
+        _ => Err(RejectReason::NoSuchGame.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::game::{config::IfClient, net::Client, World},
+        std::{net::Ipv4Addr, thread},
+    };
+
+    // a lobby on loopback, answering on its own thread until the tests end
+    fn start_lobby(empty_game_ticks: u32) -> SocketAddr {
+        let config = IfLobby {
+            lobby_addr: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0),
+            max_games: 2,
+            default_settings: GameSettings {
+                room_seed: Some(1),
+                ai_enabled: vec![],
+                ai_kind: AiKind::default(),
+            },
+        };
+        let mut lobby = Lobby::new(&config, &Rules::default(), None).unwrap();
+        lobby.empty_game_ticks = empty_game_ticks;
+        let addr = lobby.udp.local_addr().unwrap();
+        thread::spawn(move || lobby.serve());
+        addr
+    }
+    fn via(lobby_addr: SocketAddr, join: LobbyJoin) -> ViaLobby {
+        ViaLobby { lobby_addr: lobby_addr.to_string(), join }
+    }
+    fn list(lobby_addr: SocketAddr) -> Vec<GameListing> {
+        let udp = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        udp.connect(lobby_addr).unwrap();
+        udp.set_read_timeout(Some(REQUEST_RESEND)).unwrap();
+        let is_list = |msg: &LobbyMsg| matches!(msg, LobbyMsg::StcList { .. });
+        let deadline = Instant::now() + JOIN_PATIENCE;
+        match request(&udp, &mut [0; BUF_CAP], &LobbyMsg::CtsList, is_list, deadline).unwrap() {
+            LobbyMsg::StcList { games } => games,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn clients_list_create_and_join_games() {
+        let lobby_addr = start_lobby(EMPTY_GAME_TICKS);
+        assert!(list(lobby_addr).is_empty());
+        let settings = GameSettings {
+            room_seed: Some(5),
+            ai_enabled: vec![PlayerColor::Blue],
+            ai_kind: AiKind::Sink,
+        };
+        let created = find_game(&via(lobby_addr, LobbyJoin::Create(settings))).unwrap();
+        let games = list(lobby_addr);
+        assert_eq!(games.len(), 1);
+        assert_eq!((games[0].room_seed, games[0].num_clients), (5, 0));
+        assert_eq!(games[0].ai_enabled, vec![PlayerColor::Blue]);
+        let joined = find_game(&via(lobby_addr, LobbyJoin::Game(games[0].id))).unwrap();
+        assert_eq!(joined, created);
+        assert_eq!(find_game(&via(lobby_addr, LobbyJoin::AnyOpen)).unwrap(), created);
+        let unknown = find_game(&via(lobby_addr, LobbyJoin::Game(games[0].id + 1)));
+        assert!(matches!(unknown, Err(JoinError::Rejected(RejectReason::NoSuchGame))));
+    }
+
+    #[test]
+    fn games_close_once_their_clients_are_gone() {
+        let lobby_addr = start_lobby(10);
+        let game_addr = find_game(&via(lobby_addr, LobbyJoin::AnyOpen)).unwrap();
+        let config = IfClient {
+            server_addr: String::new(), // given to `Client::new` instead
+            preferred_color: PlayerColor::Blue,
+            interpolation_delay_ticks: 6,
+            via_lobby: None,
+            discover: None,
+            spectate: false,
+            password: None,
+        };
+        let (mut client, mut world, color) = Client::new(&config, game_addr, None).unwrap();
+        for _ in 0..60 {
+            let World { rules, entities, .. } = &mut world;
+            client.update(color, rules, entities, |_| {}).unwrap();
+            thread::sleep(TICK_DURATION);
+        }
+        assert_eq!(list(lobby_addr)[0].num_clients, 1, "the game closed while it was played");
+        // the client crashed, or quit without a word
+        drop(client);
+        let started = Instant::now();
+        while !list(lobby_addr).is_empty() {
+            assert!(started.elapsed() < Duration::from_secs(15), "the game never closed");
+            thread::sleep(TICK_DURATION * 10);
+        }
+    }
+
+    #[test]
+    fn finding_a_game_gives_up_if_the_lobby_is_down() {
+        // nothing listens there once it is dropped
+        let lobby_addr = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap().local_addr().unwrap();
+        let patience = Duration::from_secs(2);
+        let started = Instant::now();
+        let found = find_game_within(&via(lobby_addr, LobbyJoin::AnyOpen), patience);
+        assert!(matches!(found, Err(JoinError::Net(NetError::ServerUnreachable(_)))));
+        assert!(started.elapsed() < patience + REQUEST_RESEND * 2);
+    }
+}
@@ -1,37 +1,46 @@
 pub mod ai;
+pub mod bot;
+pub mod chat;
 pub mod config;
+pub mod discovery;
+mod font;
+pub mod lobby;
 pub mod net;
 pub mod rendering;
 pub mod room;
+pub mod rules;
+#[cfg(test)]
+mod tests;
 
 use {
-    crate::{prelude::*, rng::Rng},
-    ai::{Ai, AiExt, MiniMaxAi},
-    config::{Config, InputConfig},
+    crate::{bit_set::BitIndex, prelude::*, rng::Rng},
+    ai::{Ai, AiExt, MiniMaxAi, PathLengthsAi, SinkAi, TeamAi},
+    bot::ExternalAi,
+    chat::{ChatLine, Marker},
+    config::{AiKind, Config, IfServer, InputConfig, NetSimConfig},
     gfx_2020::{gfx_hal::Backend, winit::event::ElementState, *},
-    net::{Client, Server},
-    room::{Coord, Room, CELL_SIZE, TOT_CELL_COUNT},
+    net::{
+        reliable::Event,
+        stats::{NetStats, TICKS_PER_SEC},
+        Client, JoinError, Migrated, Migration, NetError, Server,
+    },
+    room::{Coord, Room, CELL_SIZE, HALF_CELL_SIZE, TOT_CELL_COUNT},
+    rules::Rules,
+    std::{collections::VecDeque, iter},
 };
 
-pub const MOVE_SPEED: u16 = 16;
-pub const MOVE_SIZE: Size = CELL_SIZE.scalar_div(MOVE_SPEED);
-pub const MOVE_SIZE_DIAG: Size = MOVE_SIZE.scalar_mul(5).scalar_div(7);
-
 pub const NUM_DRAW_INFOS: usize = 4;
 
-pub const PLAYER_SIZE: Size = CELL_SIZE.scalar_div(9).scalar_mul(4);
-pub const TELEPORTER_SIZE: Size = CELL_SIZE.scalar_div(2);
-pub const WALL_SIZE: DimMap<Size> = DimMap::new([
-    Size::new([CELL_SIZE.arr[0], CELL_SIZE.arr[1] / 7]),
-    Size::new([CELL_SIZE.arr[0] / 7, CELL_SIZE.arr[1]]),
-]);
 pub const ZERO_POS: Pos = Pos::new([WrapInt::ZERO; 2]);
 
-// allows an upper bound for renderer's instance buffers
-pub const NUM_TELEPORTERS: u32 = TOT_CELL_COUNT as u32 / 64;
+// allows an upper bound for renderer's instance buffers. Few enough that there is always
+// free space for another teleporter, and a wall for another door
+pub const MAX_TELEPORTERS: u32 = TOT_CELL_COUNT as u32 / 16;
 pub const NUM_PLAYERS: u32 = 3;
 pub const MAX_WALLS: u32 = TOT_CELL_COUNT as u32 * 2;
-pub const NUM_MY_DOORS: u32 = MAX_WALLS as u32 / 64;
+// also bounded by the bits of `MyDoorIndexSet`
+pub const MAX_MY_DOORS: u32 = MAX_WALLS as u32 / 32;
+const HEADLESS_NET_STATS_TICKS: u16 = TICKS_PER_SEC * 10;
 
 trait PlayerArrExt<T> {
     fn new_with<F: FnMut(PlayerColor) -> T>(func: F) -> Self;
@@ -43,21 +52,31 @@ pub type Pos = DimMap<WrapInt>;
 pub type Size = DimMap<u16>;
 pub type Vel = DimMap<Option<Sign>>;
 pub type PlayerArr<T> = [T; NUM_PLAYERS as usize];
+pub type Ais = PlayerArr<Option<Box<dyn Ai>>>;
 
 #[derive(Eq, PartialEq, Copy, Clone, Default)]
 pub struct MyDoorIndexSet {
     bits: u16,
 }
-#[derive(Copy, Clone)]
-pub struct MyDoor {
-    moving_through: Option<Sign>,
-    coord: Coord,
-    dim: Dim,
+// a gap in the wall at `coord` that only its owner can pass through. Owned by the server
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Door {
+    pub coord: Coord,
+    pub dim: Dim,
 }
+pub type DoorSet = Vec<Door>; // `Rules::num_my_doors` of them
 
 pub enum Net {
-    Server { server: Server, ais: PlayerArr<Option<MiniMaxAi>> },
+    Server { server: Server, ais: Ais },
     Client(Client),
+    // lost the server. Meanwhile, I play on alone
+    Migrating(Migration),
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum Camera {
+    Following(PlayerColor),
+    FreeRoam(Pos), // moved with the movement keys
 }
 
 #[repr(u8)]
@@ -67,16 +86,24 @@ pub enum PlayerColor {
     Blue = 1,
     Orange = 2,
 }
+// `size` is from the center to each edge
 struct Rect {
     center: Pos,
     size: Size,
 }
+struct Circle {
+    center: Pos,
+    radius: f32, // in cells
+}
 pub struct GameState {
     pub world: World,
-    pub my_doors: [MyDoor; NUM_MY_DOORS as usize],
-    // pub currently_inside_doors: MyDoorIndexSet,
-    // controlling
-    pub controlling: PlayerColor,
+    // per door of mine: Some(sign) while I am moving through it, in that direction
+    pub moving_through: [Option<Sign>; MAX_MY_DOORS as usize],
+    // doors I went through, which stay shut until the server tells me where they moved
+    pub used_doors: MyDoorIndexSet,
+    // controlling. None if spectating
+    pub controlling: Option<PlayerColor>,
+    pub camera: Camera,
     pub pressing_state: PressingState,
     pub input_config: InputConfig,
     // rendering
@@ -84,16 +111,32 @@ pub struct GameState {
     pub draw_infos: [DrawInfo; NUM_DRAW_INFOS], // four replicas of all instances to pan the maze indefinitely
     pub net: Net,
     pub local_rng: Rng,
+    // chat. Some while the chat prompt is open
+    pub chat_draft: Option<String>,
+    pub markers: VecDeque<Marker>,    // oldest first
+    pub chat_log: VecDeque<ChatLine>, // oldest first
+    pub show_net_stats: bool,
+    pub ticks_until_net_stats: u16,
+}
+// hosts a game without a window or a player of its own. Every color is free for clients.
+pub struct HeadlessGame {
+    pub world: World,
+    pub server: Server,
+    pub ais: Ais,
+    pub local_rng: Rng,
+    pub ticks_until_net_stats: u16,
 }
 pub struct World {
     pub room: Room,
     pub entities: Entities,
+    pub doors: PlayerArr<DoorSet>,
+    pub rules: Rules,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Entities {
     pub players: PlayerArr<Player>,
-    pub teleporters: [Pos; NUM_TELEPORTERS as usize],
+    pub teleporters: Vec<Pos>, // `Rules::num_teleporters` of them
 }
 
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
@@ -140,6 +183,9 @@ impl MyDoorIndexSet {
     pub fn contains(self, idx: usize) -> bool {
         self.inserted(idx) == self
     }
+    pub fn remove(&mut self, idx: usize) {
+        self.bits &= !(1 << idx);
+    }
 }
 
 impl Into<usize> for PlayerColor {
@@ -168,81 +214,96 @@ impl PlayerColor {
         }
     }
 }
-impl Player {
-    // only call IF you know you're colliding
-    fn snap_pos_wrt_vel(&mut self, rect: &Rect) {
-        fn snap_pos_dim_to(player: &mut Player, rect: &Rect, dir: Direction) {
-            player.pos[dir.dim()] = rect.aligned_to_edge(-dir);
-        }
-        match [self.vel[X], self.vel[Y]] {
-            [None, None] => {}
-            [Some(sign), None] => snap_pos_dim_to(self, rect, X.sign(sign)),
-            [None, Some(sign)] => snap_pos_dim_to(self, rect, Y.sign(sign)),
-            [Some(x_sign), Some(y_sign)] => {
-                let new_x = rect.aligned_to_edge(X.sign(!x_sign));
-                let new_y = rect.aligned_to_edge(Y.sign(!y_sign));
-                if (new_x - self.pos[X]).distance_from_zero()
-                    < (new_y - self.pos[Y]).distance_from_zero()
-                {
-                    self.pos[X] = new_x;
-                } else {
-                    self.pos[Y] = new_y;
-                }
-            }
-        }
+impl Rect {
+    // from my nearest point to `pt`, in cells. zero if I contain `pt`
+    fn offset_to(&self, pt: Pos) -> Vec2 {
+        let half = self.size.in_cells();
+        let offset = (pt - self.center).in_cells();
+        offset - offset.max(-half).min(half)
     }
 }
-impl Rect {
-    fn aligned_to_edge(&self, dir: Direction) -> WrapInt {
-        let dim = dir.dim();
-        self.center[dim] + dir.sign() * WrapInt::from(self.size[dim])
+impl Circle {
+    fn player(rules: &Rules, center: Pos) -> Self {
+        Self { center, radius: rules.player_radius() }
+    }
+    fn overlaps(&self, other: &Self) -> bool {
+        let reach = self.radius + other.radius;
+        (other.center - self.center).in_cells().length_squared() < reach * reach
     }
-    fn contains(&self, pt: Pos) -> bool {
-        Dim::iter_domain()
-            .all(|dim| (pt[dim] - self.center[dim]).distance_from_zero() < self.size[dim])
+    fn overlaps_rect(&self, rect: &Rect) -> bool {
+        rect.offset_to(self.center).length_squared() < self.radius * self.radius
+    }
+    // my center, moved the shortest way out of `rect`. Only the push along the surface normal
+    // is applied, so movement along the surface is kept and corners are rounded off
+    fn pushed_out_of(&self, rect: &Rect) -> Pos {
+        let offset = rect.offset_to(self.center);
+        let dist = offset.length();
+        let push = if dist >= self.radius {
+            Vec2::zero()
+        } else if dist > 0. {
+            offset * ((self.radius - dist) / dist)
+        } else {
+            // center is inside: leave through the nearest edge
+            let from_center = (self.center - rect.center).in_cells();
+            let depth = rect.size.in_cells() - from_center.abs() + Vec2::splat(self.radius);
+            let sign = |val: f32| if val < 0. { -1. } else { 1. };
+            if depth.x < depth.y {
+                Vec2::new(depth.x * sign(from_center.x), 0.)
+            } else {
+                Vec2::new(0., depth.y * sign(from_center.y))
+            }
+        };
+        self.center + Pos::from_cells(push)
     }
 }
 impl Entities {
-    pub fn random(rng: &mut Rng) -> Self {
-        let mut me = Self { players: Default::default(), teleporters: Default::default() };
+    pub fn random(rng: &mut Rng, rules: &Rules) -> Self {
+        let mut me = Self { players: Default::default(), teleporters: vec![] };
         for i in 0..NUM_PLAYERS as usize {
             me.players[i].pos = me.random_free_space(rng);
         }
-        for i in 0..NUM_TELEPORTERS as usize {
-            me.teleporters[i] = me.random_free_space(rng);
+        for _ in 0..rules.num_teleporters {
+            let pos = me.random_free_space(rng);
+            me.teleporters.push(pos);
         }
         me
     }
+    // uniformly distributed over `free_space_coords`
     pub fn random_free_space(&self, rng: &mut Rng) -> Pos {
-        pub const MIN_DIST: Size = CELL_SIZE.scalar_mul(2);
         loop {
-            let new = Coord::random(rng).center_pos();
-            let mut pos_iter =
-                self.teleporters.iter().copied().chain(self.players.iter().map(|p| p.pos));
-            if pos_iter.all(move |pos| {
-                Dim::iter_domain()
-                    .any(move |dim| (pos[dim] - new[dim]).distance_from_zero() >= MIN_DIST[dim])
-            }) {
-                return new;
+            let new = Coord::random(rng);
+            if self.is_free_space(new) {
+                return new.center_pos();
             }
         }
     }
+    pub fn free_space_coords(&self) -> impl Iterator<Item = Coord> + '_ {
+        BitIndex::iter_domain()
+            .map(Coord::from_bit_index)
+            .filter(move |&coord| self.is_free_space(coord))
+    }
+    fn is_free_space(&self, coord: Coord) -> bool {
+        pub const MIN_DIST: Size = CELL_SIZE.scalar_mul(2);
+        let new = coord.center_pos();
+        let mut pos_iter =
+            self.teleporters.iter().copied().chain(self.players.iter().map(|p| p.pos));
+        pos_iter.all(move |pos| {
+            Dim::iter_domain()
+                .any(move |dim| (pos[dim] - new[dim]).distance_from_zero() >= MIN_DIST[dim])
+        })
+    }
 }
 
 impl Room {
-    fn random_new_my_doors(&self, rng: &mut Rng) -> [MyDoor; NUM_MY_DOORS as usize] {
-        let mut my_doors = [MyDoor { coord: Default::default(), dim: X, moving_through: None };
-            NUM_MY_DOORS as usize];
-        for i in 0..NUM_MY_DOORS as usize {
-            my_doors[i] = self.random_new_my_door(rng, &my_doors);
+    fn random_new_my_doors(&self, rng: &mut Rng, rules: &Rules) -> DoorSet {
+        let mut my_doors = Vec::with_capacity(rules.num_my_doors as usize);
+        for _ in 0..rules.num_my_doors {
+            let door = self.random_new_my_door(rng, &my_doors);
+            my_doors.push(door);
         }
         my_doors
     }
-    fn random_new_my_door(
-        &self,
-        rng: &mut Rng,
-        my_doors: &[MyDoor; NUM_MY_DOORS as usize],
-    ) -> MyDoor {
+    fn random_new_my_door(&self, rng: &mut Rng, my_doors: &[Door]) -> Door {
         loop {
             let coord = Coord::random(rng);
             // 1. check if its far away enough
@@ -255,127 +316,136 @@ impl Room {
                 if let Some(dim) =
                     dim_iter.filter(|&dim| self.wall_sets[dim].contains(coord.bit_index())).next()
                 {
-                    return MyDoor { coord, dim, moving_through: None };
+                    return Door { coord, dim };
                 }
             }
         }
     }
 }
-impl GameState {
-    fn update_net_and_ais(&mut self) {
-        match &mut self.net {
-            Net::Server { server, ais } => {
-                for col in PlayerColor::iter_domain() {
-                    if let Some(ai) = &mut ais[col] {
-                        self.world.entities.players[col].vel =
-                            ai.update(&self.world, &mut self.local_rng);
-                    }
-                }
-
-                let new_client_callback = move |color: PlayerColor, entities: &mut Entities| {
-                    ais[color] = None;
-                    entities.players[color].vel = Vel::default();
-                };
-                server.update(self.controlling, &mut self.world.entities, new_client_callback)
+// the positions from `from` to `to`, in steps no longer than `Rules::max_sweep_step`
+fn sweep_positions(rules: &Rules, from: Pos, to: Pos) -> impl Iterator<Item = Pos> {
+    let diff = to - from;
+    let max_step = rules.max_sweep_step();
+    let steps = Dim::iter_domain()
+        .map(|dim| {
+            let dist = diff[dim].distance_from_zero() as u32;
+            (dist + max_step[dim] as u32 - 1) / max_step[dim] as u32
+        })
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    (1..=steps).map(move |step| {
+        Pos::new_xy_with(|dim| {
+            let offset = Into::<i16>::into(diff[dim]) as i32 * step as i32 / steps as i32;
+            from[dim] + WrapInt::from(offset as i16)
+        })
+    })
+}
+impl World {
+    // where a player of `color` moving from `from` toward `to` ends up, stopped by the first wall
+    // it hits that isn't its door. Like the controlled player, but with no regard for which doors
+    // were used. The server sweeps every player it moves
+    fn swept(&self, color: PlayerColor, from: Pos, to: Pos) -> Pos {
+        let mut at = from;
+        for pos in sweep_positions(&self.rules, from, to) {
+            let (pushed, stopped) = self.pushed_out_of_walls(color, pos);
+            at = pushed;
+            if stopped {
+                break;
             }
-            Net::Client(client) => client.update(self.controlling, &mut self.world.entities),
         }
+        at
     }
-    fn move_and_collide(&mut self) {
-        // TODO if I am inside a door, mutate vel s.t. I continue going through
-        // player movement
-        for col in PlayerColor::iter_domain() {
-            let player = &mut self.world.entities.players[col];
-            let mut effective_vel = player.vel;
-            if col == self.controlling {
-                // override the player's input vel to continue moving them through the door
-                for my_door in self.my_doors.iter() {
-                    if let Some(sign) = my_door.moving_through {
-                        effective_vel[!my_door.dim] = Some(sign);
-                    }
+    // `pos`, pushed out of the walls around it that aren't doors of `color`. true if it was
+    fn pushed_out_of_walls(&self, color: PlayerColor, mut pos: Pos) -> (Pos, bool) {
+        let mut pushed = false;
+        for dim in Dim::iter_domain() {
+            for coord in Room::wall_cells_to_check_at(pos, dim) {
+                if !self.room.wall_sets[dim].contains(coord.bit_index())
+                    || self.doors[color].contains(&Door { coord, dim })
+                {
+                    continue;
                 }
-            }
-            let move_size = if effective_vel[X].is_some() && effective_vel[Y].is_some() {
-                MOVE_SIZE_DIAG
-            } else {
-                MOVE_SIZE
-            };
-            for dim in Dim::iter_domain() {
-                if let Some(sign) = effective_vel[dim] {
-                    player.pos[dim] += sign * WrapInt::from(move_size[dim]);
+                let rect = Rect {
+                    center: GameState::wall_pos(coord, dim),
+                    size: self.rules.wall_size(dim).scalar_div(2),
+                };
+                let circle = Circle::player(&self.rules, pos);
+                if circle.overlaps_rect(&rect) {
+                    pos = circle.pushed_out_of(&rect);
+                    pushed = true;
                 }
             }
         }
-
-        if let Net::Server { ais, .. } = &mut self.net {
-            // player -> player collision
-            for predator in PlayerColor::iter_domain() {
-                let prey = predator.prey();
-                let rect =
-                    Rect { center: self.world.entities.players[prey].pos, size: PLAYER_SIZE };
-                if rect.contains(self.world.entities.players[predator].pos) {
-                    self.world.entities.players[prey].pos =
-                        self.world.entities.random_free_space(&mut self.local_rng);
-                    if let Some(ai) = &mut ais[prey] {
-                        ai.i_was_moved(&self.world);
-                    }
-                }
+        (pos, pushed)
+    }
+    // moves me, the controlled player, toward `dest` in short steps, stopping at the first wall I
+    // hit. Unlike `swept`, my used doors stay shut, and `moving_through` follows me through the
+    // others. Returns the doors I finished moving through
+    fn swept_through_my_doors(
+        &mut self,
+        color: PlayerColor,
+        dest: Pos,
+        used_doors: MyDoorIndexSet,
+        moving_through: &mut [Option<Sign>; MAX_MY_DOORS as usize],
+    ) -> MyDoorIndexSet {
+        let start = self.entities.players[color].pos;
+        let mut moving_through_doors = MyDoorIndexSet::default(); // still building...
+        for pos in sweep_positions(&self.rules, start, dest) {
+            self.entities.players[color].pos = pos;
+            if self.collide_with_walls(color, used_doors, moving_through, &mut moving_through_doors)
+            {
+                break;
             }
-
-            // player -> teleporter collision
-            for i in 0..self.world.entities.players.len() {
-                let player_pos = self.world.entities.players[i].pos;
-                for j in 0..self.world.entities.teleporters.len() {
-                    let teleporter = self.world.entities.teleporters[j];
-                    let rect = Rect {
-                        center: teleporter,
-                        size: (PLAYER_SIZE + TELEPORTER_SIZE).map(|val| val / 2u16),
-                    };
-                    if rect.contains(player_pos) {
-                        self.world.entities.players[i].pos =
-                            self.world.entities.random_free_space(&mut self.local_rng);
-                        self.world.entities.teleporters[j] =
-                            self.world.entities.random_free_space(&mut self.local_rng);
-                        if let Some(ai) = &mut ais[i] {
-                            ai.i_was_moved(&self.world);
-                        }
-                    }
-                }
+        }
+        let mut passed_doors = MyDoorIndexSet::default();
+        for i in (0..MAX_MY_DOORS as usize).filter(|&i| !moving_through_doors.contains(i)) {
+            if moving_through[i].take().is_some() {
+                passed_doors.insert(i);
             }
         }
-
-        // player -> wall collision
-        let player = &mut self.world.entities.players[self.controlling];
-        let mut moving_through_doors = MyDoorIndexSet::default(); // still building...
+        passed_doors
+    }
+    // pushes me out of walls, noting my doors I'm moving through. true if a wall stopped me
+    fn collide_with_walls(
+        &mut self,
+        controlling: PlayerColor,
+        used_doors: MyDoorIndexSet,
+        moving_through: &mut [Option<Sign>; MAX_MY_DOORS as usize],
+        moving_through_doors: &mut MyDoorIndexSet,
+    ) -> bool {
+        let mut stopped = false;
+        let rules = &self.rules;
+        let player = &mut self.entities.players[controlling];
         for dim in Dim::iter_domain() {
             for coord in Room::wall_cells_to_check_at(player.pos, dim) {
-                let wall_here = self.world.room.wall_sets[dim].contains(coord.bit_index());
+                let wall_here = self.room.wall_sets[dim].contains(coord.bit_index());
                 if !wall_here {
                     // no wall -> no door. nothing to do here.
                     continue;
                 }
                 let rect = Rect {
                     center: GameState::wall_pos(coord, dim),
-                    size: (WALL_SIZE[dim] + PLAYER_SIZE).map(|val| val / 2u16),
+                    size: rules.wall_size(dim).scalar_div(2),
                 };
-                let colliding = rect.contains(player.pos);
+                let colliding = Circle::player(rules, player.pos).overlaps_rect(&rect);
                 if !colliding {
                     // no wall collision -> no door collision.
                     continue;
                 }
-                let my_door_here_idx = self
-                    .my_doors
+                let my_door_here_idx = self.doors[controlling]
                     .iter()
                     .enumerate()
+                    .filter(|&(index, _)| !used_doors.contains(index))
                     .filter(|(_, my_door)| my_door.dim == dim && my_door.coord == coord)
                     .map(|(index, _)| index)
                     .next();
 
                 if let Some(i) = my_door_here_idx {
-                    let my_door = &mut self.my_doors[i];
-                    if my_door.moving_through.is_none() {
+                    let moving_through = &mut moving_through[i];
+                    if moving_through.is_none() {
                         // moving through door START
-                        my_door.moving_through = Some(if player.pos[!dim] < rect.center[!dim] {
+                        *moving_through = Some(if player.pos[!dim] < rect.center[!dim] {
                             Positive
                         } else {
                             Negative
@@ -383,52 +453,428 @@ impl GameState {
                     }
                     moving_through_doors.insert(i);
                 } else {
-                    player.snap_pos_wrt_vel(&rect);
+                    player.pos = Circle::player(rules, player.pos).pushed_out_of(&rect);
+                    stopped = true;
+                }
+            }
+        }
+        stopped
+    }
+    // true IFF a player of `color` could get from `from` to `to` without passing through walls,
+    // except its doors (and `extra_door`). Checked cell by cell along the straight line
+    fn path_is_open(
+        &self,
+        color: PlayerColor,
+        from: Pos,
+        to: Pos,
+        extra_door: Option<Door>,
+    ) -> bool {
+        let is_open = |coord: Coord, dir: Direction| {
+            let (coord, dim) = Room::wall_between(coord, dir);
+            !self.room.wall_sets[dim].contains(coord.bit_index())
+                || self.doors[color]
+                    .iter()
+                    .chain(extra_door.iter())
+                    .any(|door| *door == Door { coord, dim })
+        };
+        let diff = DimMap::new_xy_with(|dim| Into::<i16>::into(to[dim] - from[dim]) as i32);
+        // steps of at most half a cell, so consecutive cells are neighbours
+        let steps = Dim::iter_domain()
+            .map(|dim| diff[dim].abs() / HALF_CELL_SIZE[dim] as i32 + 1)
+            .max()
+            .unwrap();
+        let mut at = Coord::from_pos_flooring(from);
+        for step in 1..=steps {
+            let pos = Pos::new_xy_with(|dim| from[dim] + (diff[dim] * step / steps) as i16);
+            let next = Coord::from_pos_flooring(pos);
+            let mut dirs = Dim::iter_domain().filter_map(|dim| {
+                let sign = (next.corner_pos()[dim] - at.corner_pos()[dim]).sign()?;
+                Some(dim.sign(sign))
+            });
+            let open = match (dirs.next(), dirs.next()) {
+                (None, _) => true,
+                (Some(dir), None) => is_open(at, dir),
+                // cut a corner. Either way around will do
+                (Some(a), Some(b)) => {
+                    (is_open(at, a) && is_open(at.stepped(a), b))
+                        || (is_open(at, b) && is_open(at.stepped(b), a))
                 }
+            };
+            if !open {
+                return false;
             }
+            at = next;
         }
-        for i in (0..NUM_MY_DOORS as usize).filter(|&i| !moving_through_doors.contains(i)) {
-            if self.my_doors[i].moving_through.take().is_some() {
-                // moving through door END
-                self.my_doors[i] =
-                    self.world.room.random_new_my_door(&mut self.local_rng, &self.my_doors);
+        true
+    }
+    // players catching players and using teleporters. Only the server decides these
+    fn server_collisions(
+        &mut self,
+        server: &mut Server,
+        ais: &mut Ais,
+        rng: &mut Rng,
+    ) -> Vec<Event> {
+        let mut events = vec![];
+        // player -> player collision
+        for predator in PlayerColor::iter_domain() {
+            let prey = predator.prey();
+            let predator_pos = self.entities.players[predator].pos;
+            // where the prey is now, or where the predator's client saw it
+            let mut prey_positions =
+                iter::once(self.entities.players[prey].pos).chain(server.seen_pos(predator, prey));
+            let predator_circle = Circle::player(&self.rules, predator_pos);
+            let rules = &self.rules;
+            if prey_positions.any(|center| Circle::player(rules, center).overlaps(&predator_circle))
+            {
+                self.entities.players[prey].pos = self.entities.random_free_space(rng);
+                server.on_relocated(prey);
+                if let Some(ai) = &mut ais[prey] {
+                    ai.i_was_moved(self);
+                }
+                let event = Event::Captured { predator, prey };
+                server.broadcast(event.clone());
+                events.push(event);
             }
         }
+
+        // player -> teleporter collision
+        let teleporter_size = self.rules.teleporter_size().scalar_div(2);
+        for i in 0..self.entities.players.len() {
+            let player_circle = Circle::player(&self.rules, self.entities.players[i].pos);
+            for j in 0..self.entities.teleporters.len() {
+                let teleporter = self.entities.teleporters[j];
+                let rect = Rect { center: teleporter, size: teleporter_size };
+                if player_circle.overlaps_rect(&rect) {
+                    self.entities.players[i].pos = self.entities.random_free_space(rng);
+                    server.on_relocated(PlayerColor::ALL[i]);
+                    self.entities.teleporters[j] = self.entities.random_free_space(rng);
+                    if let Some(ai) = &mut ais[i] {
+                        ai.i_was_moved(self);
+                    }
+                }
+            }
+        }
+        events
     }
-    pub fn new<B: Backend>(renderer: &mut Renderer<B>, config: &Config) -> Self {
+}
+fn new_ai(kind: AiKind, color: PlayerColor, world: &World, rng: &mut Rng) -> Box<dyn Ai> {
+    match kind {
+        AiKind::MiniMax => Box::new(MiniMaxAi::new(color, world, rng)),
+        AiKind::Sink => Box::new(SinkAi::new(color, world, rng)),
+        AiKind::PathLengths => Box::new(PathLengthsAi::new(color, world, rng)),
+        AiKind::Team => Box::new(TeamAi::new(color, world, rng)),
+    }
+}
+fn new_ais(
+    config: &IfServer,
+    world: &World,
+    controlling: Option<PlayerColor>,
+    rng: &mut Rng,
+) -> Ais {
+    let mut ais = Ais::default();
+    for bot in config.bots.iter() {
+        if ais[bot.color].is_none() && Some(bot.color) != controlling {
+            match ExternalAi::new(bot.color, bot) {
+                Ok(ai) => ais[bot.color] = Some(Box::new(ai)),
+                Err(e) => println!("Failed to start {:?} bot: {}", bot.color, e),
+            }
+        }
+    }
+    for &col in config.ai_enabled.iter() {
+        if ais[col].is_none() && Some(col) != controlling {
+            ais[col] = Some(new_ai(config.ai_kind, col, world, rng));
+        }
+    }
+    ais
+}
+fn print_net_stats(server: &Server) {
+    for (color, addr, stats) in server.stats() {
+        match color {
+            Some(color) => println!("{:?} at {}: {}", color, addr, stats),
+            None => println!("Spectator at {}: {}", addr, stats),
+        }
+    }
+}
+fn update_server_and_ais(
+    server: &mut Server,
+    ais: &mut Ais,
+    controlling: Option<PlayerColor>,
+    world: &mut World,
+    rng: &mut Rng,
+    event_callback: impl FnMut(Event),
+) -> Result<(), NetError> {
+    // AIs wait while clients play their colors, and play them again once they leave
+    for col in PlayerColor::iter_domain().filter(|&col| !server.has_client(col)) {
+        if let Some(ai) = &mut ais[col] {
+            world.entities.players[col].vel = ai.update(world, rng);
+        }
+    }
+    let new_client_callback = |color: PlayerColor, entities: &mut Entities| {
+        entities.players[color].vel = Vel::default();
+    };
+    server.update(controlling, world, new_client_callback, event_callback)?;
+    for col in server.take_freed_colors() {
+        world.entities.players[col].vel = Vel::default();
+        if let Some(ai) = &mut ais[col] {
+            ai.i_was_moved(world); // by the client, since it last played
+        }
+    }
+    Ok(())
+}
+impl HeadlessGame {
+    pub fn new(
+        config: &IfServer,
+        rules: &Rules,
+        net_sim: Option<&NetSimConfig>,
+    ) -> Result<Self, NetError> {
+        let mut local_rng = Rng::new_seeded(Rng::random_seed());
+        let (server, world, _) = Server::new(config, rules, net_sim)?;
+        let ais = new_ais(config, &world, None, &mut local_rng);
+        Ok(Self { world, server, ais, local_rng, ticks_until_net_stats: HEADLESS_NET_STATS_TICKS })
+    }
+    // Err if the game can no longer be served
+    pub fn update(&mut self) -> Result<(), NetError> {
+        let world = &mut self.world;
+        for color in PlayerColor::iter_domain() {
+            let player = &world.entities.players[color];
+            let to = player.pos.moved(&world.rules, player.vel, 1);
+            world.entities.players[color].pos = world.swept(color, player.pos, to);
+        }
+        // nobody is watching. events reach clients through the server
+        let _events =
+            self.world.server_collisions(&mut self.server, &mut self.ais, &mut self.local_rng);
+        let Self { server, ais, world, local_rng, .. } = self;
+        update_server_and_ais(server, ais, None, world, local_rng, |_| {})?;
+        self.ticks_until_net_stats -= 1;
+        if self.ticks_until_net_stats == 0 {
+            self.ticks_until_net_stats = HEADLESS_NET_STATS_TICKS;
+            print_net_stats(&self.server);
+        }
+        Ok(())
+    }
+}
+impl GameState {
+    fn update_net_and_ais(&mut self) -> Proceed {
+        if let Net::Migrating(migration) = &mut self.net {
+            return match migration.poll() {
+                Ok(None) => Ok(()),
+                Ok(Some(migrated)) => {
+                    self.migrated(migrated);
+                    Ok(())
+                }
+                Err(e) => {
+                    println!("Disconnected: {}", e);
+                    Err(HaltLoop)
+                }
+            };
+        }
+        let mut events = vec![];
+        let result = match &mut self.net {
+            Net::Server { server, ais } => update_server_and_ais(
+                server,
+                ais,
+                self.controlling,
+                &mut self.world,
+                &mut self.local_rng,
+                |event| events.push(event),
+            ),
+            Net::Client(client) => {
+                let World { rules, entities, .. } = &mut self.world;
+                client.update(self.controlling, rules, entities, |event| events.push(event))
+            }
+            Net::Migrating(_) => Ok(()), // polled above
+        };
+        for event in events {
+            self.handle_net_event(event);
+        }
+        match result {
+            Ok(()) => Ok(()),
+            Err(e) if e.lost_server() => {
+                println!("Lost the server: {}. Migrating", e);
+                self.migrate().map_err(|e| {
+                    println!("Disconnected: {}", e);
+                    HaltLoop
+                })
+            }
+            Err(e) => {
+                println!("Disconnected: {}", e);
+                Err(HaltLoop)
+            }
+        }
+    }
+    // starts looking for a successor of the lost server, to continue the match with
+    fn migrate(&mut self) -> Result<(), JoinError> {
+        if let Net::Client(client) = &mut self.net {
+            self.net = Net::Migrating(client.migrate(self.controlling)?);
+        }
+        Ok(())
+    }
+    // continues the match with a successor of the lost server, or as its host
+    fn migrated(&mut self, migrated: Migrated) {
+        match migrated {
+            Migrated::Host(mut server, config, mut entities, doors) => {
+                // the others continue from where the server left them. I stay where I am
+                if let Some(color) = self.controlling {
+                    entities.players[color] = self.world.entities.players[color].clone();
+                }
+                self.world.entities = entities;
+                self.world.doors = doors;
+                if let Some(color) = self.controlling {
+                    // the server I told about these is gone. Now I move them myself
+                    let used_doors = self.used_doors;
+                    let num_my_doors = self.world.doors[color].len();
+                    for i in (0..num_my_doors).filter(|&i| used_doors.contains(i)) {
+                        server.relocate_door(&mut self.world, color, i);
+                    }
+                }
+                self.used_doors = Default::default();
+                let ais = new_ais(&config, &self.world, self.controlling, &mut self.local_rng);
+                self.net = Net::Server { server, ais };
+            }
+            Migrated::Joined(client, world, controlling) => {
+                self.net = Net::Client(client);
+                self.world = world;
+                self.controlling = controlling;
+                self.moving_through = Default::default();
+                self.used_doors = Default::default();
+            }
+        }
+    }
+    // lets my clients migrate, or my server free my color, at once rather than when I go silent
+    fn quit(&mut self) -> Proceed {
+        match &mut self.net {
+            Net::Server { server, .. } => server.leave(),
+            Net::Client(client) => client.leave(),
+            Net::Migrating(_) => {}
+        }
+        Err(HaltLoop)
+    }
+    fn handle_net_event(&mut self, event: Event) {
+        match event {
+            Event::Captured { predator, prey } => println!("{:?} caught {:?}!", predator, prey),
+            Event::DoorMoved { color, index, door } => {
+                if let Some(slot) = self.world.doors[color].get_mut(index as usize) {
+                    *slot = door;
+                    if Some(color) == self.controlling {
+                        self.used_doors.remove(index as usize);
+                    }
+                }
+            }
+            Event::DoorUsed { .. } => {} // only the server handles these
+            event @ Event::Chat { .. }
+            | event @ Event::Ping { .. }
+            | event @ Event::ChatDropped => self.show_chat_or_ping(event),
+        }
+    }
+    fn move_and_collide(&mut self) {
+        // TODO if I am inside a door, mutate vel s.t. I continue going through
+        // player movement
+        let mut my_dest = None;
+        for col in PlayerColor::iter_domain() {
+            let player = &self.world.entities.players[col];
+            let mut effective_vel = player.vel;
+            if Some(col) == self.controlling {
+                // override the player's input vel to continue moving them through the door
+                for (my_door, moving_through) in
+                    self.world.doors[col].iter().zip(self.moving_through.iter())
+                {
+                    if let Some(sign) = *moving_through {
+                        effective_vel[!my_door.dim] = Some(sign);
+                    }
+                }
+                // swept below, against the walls
+                my_dest = Some((col, player.pos.moved(&self.world.rules, effective_vel, 1)));
+                continue;
+            }
+            // AIs and remote players. Remote ones are corrected by their clients' updates
+            let to = player.pos.moved(&self.world.rules, effective_vel, 1);
+            self.world.entities.players[col].pos = self.world.swept(col, player.pos, to);
+        }
+        // player -> wall collision. spectators don't collide
+        if let Some((controlling, dest)) = my_dest {
+            self.sweep_to(controlling, dest);
+        }
+
+        let events = match &mut self.net {
+            Net::Server { ais, server } => {
+                self.world.server_collisions(server, ais, &mut self.local_rng)
+            }
+            Net::Client(_) | Net::Migrating(_) => vec![],
+        };
+        for event in events {
+            self.handle_net_event(event);
+        }
+    }
+    // moves me toward `dest` in short steps, stopping at the first wall I hit
+    fn sweep_to(&mut self, controlling: PlayerColor, dest: Pos) {
+        let passed_doors = self.world.swept_through_my_doors(
+            controlling,
+            dest,
+            self.used_doors,
+            &mut self.moving_through,
+        );
+        for i in (0..MAX_MY_DOORS as usize).filter(|&i| passed_doors.contains(i)) {
+            // moving through door END. The server moves it elsewhere
+            match &mut self.net {
+                Net::Server { server, .. } => server.relocate_door(&mut self.world, controlling, i),
+                Net::Client(client) => {
+                    self.used_doors.insert(i);
+                    client.send_event(Event::DoorUsed { index: i as u8 });
+                }
+                // relocated once I host, or forgotten once I join a successor
+                Net::Migrating(_) => self.used_doors.insert(i),
+            }
+        }
+    }
+    pub fn new<B: Backend>(
+        renderer: &mut Renderer<B>,
+        config: &Config,
+        rules: &Rules,
+    ) -> Result<Self, JoinError> {
         let tex_id = renderer.load_texture({
             let image_bytes = include_bytes!("spritesheet.png");
             &gfx_2020::load_texture_from_bytes(image_bytes).expect("Failed to decode png!")
         });
         let mut local_rng = Rng::new_seeded(Rng::random_seed());
+        let net_sim = NetSimConfig::from_env().or_else(|| config.net_sim.clone());
         let (net, world, controlling) = if config.server_mode {
-            let (server, world, controlling) = Server::new(&config.if_server);
-            let mut ais = PlayerArr::<Option<_>>::default();
-            for &col in config.if_server.ai_enabled.iter() {
-                if ais[col].is_none() && col != controlling {
-                    ais[col] = Some(MiniMaxAi::new(col, &world, &mut local_rng));
-                }
-            }
+            let (server, world, controlling) =
+                Server::new(&config.if_server, rules, net_sim.as_ref())?;
+            let ais = new_ais(&config.if_server, &world, Some(controlling), &mut local_rng);
             let net = Net::Server { server, ais };
-            (net, world, controlling)
+            (net, world, Some(controlling))
         } else {
-            let (client, world, controlling) = Client::new(&config.if_client);
+            let if_client = &config.if_client;
+            let server_addr = match (&if_client.via_lobby, &if_client.discover) {
+                (Some(via_lobby), _) => lobby::find_game(via_lobby)?,
+                (None, Some(discover)) => discovery::find_server(discover)?,
+                (None, None) => net::resolve(&if_client.server_addr)?,
+            };
+            let (client, world, controlling) =
+                Client::new(&config.if_client, server_addr, net_sim.as_ref())?;
             let net = Net::Client(client);
             (net, world, controlling)
         };
         let mut state = GameState {
-            my_doors: world.room.random_new_my_doors(&mut local_rng),
+            moving_through: Default::default(),
+            used_doors: Default::default(),
             net,
             world,
             pressing_state: Default::default(),
             tex_id,
             draw_infos: GameState::init_draw_infos(),
             controlling,
+            camera: Camera::Following(controlling.unwrap_or(PlayerColor::Black)),
             local_rng,
             input_config: config.input.clone(),
+            chat_draft: None,
+            markers: Default::default(),
+            chat_log: Default::default(),
+            show_net_stats: false,
+            ticks_until_net_stats: 0,
         };
         state.init_vertex_buffers(renderer);
-        state
+        Ok(state)
     }
     pub fn wall_pos(coord: Coord, dim: Dim) -> Pos {
         // e.g. X dim wall at Coord[0,0] has pos [0.5, 0.0]
@@ -439,8 +885,66 @@ impl GameState {
     fn update_move_key(&mut self, dir: Direction, state: ElementState) {
         let dim = dir.dim();
         self.pressing_state.map[dim].map[dir.sign()] = state;
-        self.world.entities.players[self.controlling].vel[dim] =
-            self.pressing_state.map[dim].solo_pressed();
+        if let Some(controlling) = self.controlling {
+            self.world.entities.players[controlling].vel[dim] =
+                self.pressing_state.map[dim].solo_pressed();
+        }
+    }
+    fn switch_camera(&mut self) {
+        if self.controlling.is_some() {
+            return; // players always follow themselves
+        }
+        use PlayerColor as Pc;
+        self.camera = match self.camera {
+            Camera::Following(Pc::Black) => Camera::Following(Pc::Blue),
+            Camera::Following(Pc::Blue) => Camera::Following(Pc::Orange),
+            Camera::Following(Pc::Orange) => Camera::FreeRoam(self.camera_pos()),
+            Camera::FreeRoam(_) => Camera::Following(Pc::Black),
+        };
+    }
+    fn update_camera(&mut self) {
+        const FREE_ROAM_SPEEDUP: u16 = 2;
+        let pressing_state = &self.pressing_state;
+        if let Camera::FreeRoam(pos) = &mut self.camera {
+            let vel = Vel::new_xy_with(|dim| pressing_state.map[dim].solo_pressed());
+            *pos = pos.moved(&self.world.rules, vel, FREE_ROAM_SPEEDUP);
+        }
+    }
+    // whose doors are drawn as mine. Spectators see those of the player they follow
+    pub fn door_owner(&self) -> Option<PlayerColor> {
+        match (self.controlling, self.camera) {
+            (Some(color), _) | (None, Camera::Following(color)) => Some(color),
+            (None, Camera::FreeRoam(_)) => None,
+        }
+    }
+    pub fn camera_pos(&self) -> Pos {
+        match self.camera {
+            Camera::Following(color) => self.world.entities.players[color].pos,
+            Camera::FreeRoam(pos) => pos,
+        }
+    }
+    // there are no glyphs to draw numbers with, so they are printed every second while shown
+    fn update_net_stats(&mut self) {
+        if !self.show_net_stats {
+            return;
+        }
+        if let Some(ticks) = self.ticks_until_net_stats.checked_sub(1) {
+            self.ticks_until_net_stats = ticks;
+            return;
+        }
+        self.ticks_until_net_stats = TICKS_PER_SEC;
+        match &self.net {
+            Net::Server { server, .. } => print_net_stats(server),
+            Net::Client(client) => println!("Server: {}", client.stats()),
+            Net::Migrating(_) => println!("Looking for the next host"),
+        }
+    }
+    // Some while the overlay is shown. Only clients have a single peer to show
+    pub fn net_stats_overlay(&self) -> Option<NetStats> {
+        match &self.net {
+            Net::Client(client) if self.show_net_stats => Some(client.stats()),
+            _ => None,
+        }
     }
 }
 
@@ -454,7 +958,10 @@ impl DrivesMainLoop for GameState {
 
     fn update<B: Backend>(&mut self, renderer: &mut Renderer<B>) -> Proceed {
         self.move_and_collide();
-        self.update_net_and_ais();
+        self.update_net_and_ais()?;
+        self.update_camera();
+        self.update_markers();
+        self.update_net_stats();
         self.update_vertex_buffers(renderer);
         self.update_view_transforms();
         Ok(())
@@ -467,16 +974,36 @@ impl DrivesMainLoop for GameState {
     ) -> Proceed {
         use winit::event::{Event as Ev, KeyboardInput as Ki, WindowEvent as We};
         match event {
-            Ev::WindowEvent { event: We::CloseRequested, .. } => return Err(HaltLoop),
+            Ev::WindowEvent { event: We::CloseRequested, .. } => return self.quit(),
+            Ev::WindowEvent { event: We::ReceivedCharacter(c), .. } => self.handle_chat_char(c),
             Ev::WindowEvent { event: We::KeyboardInput { input, .. }, .. } => {
                 // ok
                 match input {
                     Ki { virtual_keycode: Some(vkc), state, .. } => match vkc {
-                        x if x == self.input_config.exit => return Err(HaltLoop),
+                        _ if self.chat_draft.is_some() => self.handle_chat_key(vkc, state),
+                        x if x == self.input_config.exit => return self.quit(),
                         x if x == self.input_config.up => self.update_move_key(Up, state),
                         x if x == self.input_config.down => self.update_move_key(Down, state),
                         x if x == self.input_config.left => self.update_move_key(Left, state),
                         x if x == self.input_config.right => self.update_move_key(Right, state),
+                        x if x == self.input_config.switch_camera
+                            && state == ElementState::Pressed =>
+                        {
+                            self.switch_camera()
+                        }
+                        x if x == self.input_config.chat && state == ElementState::Pressed => {
+                            self.start_chat()
+                        }
+                        x if x == self.input_config.net_stats && state == ElementState::Pressed => {
+                            self.show_net_stats = !self.show_net_stats;
+                            self.ticks_until_net_stats = 0;
+                        }
+                        _ if state == ElementState::Pressed => {
+                            let ping = self.input_config.pings.iter().find(|(key, _)| *key == vkc);
+                            if let Some(&(_, kind)) = ping {
+                                self.ping(kind);
+                            }
+                        }
                         _ => {}
                     },
                     _ => {}
@@ -526,9 +1053,39 @@ impl Size {
         self.arr[1] /= rhs;
         self
     }
+    fn in_cells(self) -> Vec2 {
+        Vec2::new(self[X] as f32 / CELL_SIZE[X] as f32, self[Y] as f32 / CELL_SIZE[Y] as f32)
+    }
 }
 impl Pos {
+    // for offsets, e.g. the difference of two positions
+    fn in_cells(self) -> Vec2 {
+        let f = |dim| Into::<i16>::into(self[dim]) as f32 / CELL_SIZE[dim] as f32;
+        Vec2::new(f(X), f(Y))
+    }
+    // rounds away from zero, so a push out of a rect does clear it
+    fn from_cells(cells: Vec2) -> Self {
+        let f = |val: f32, dim| {
+            let units = val * CELL_SIZE[dim] as f32;
+            WrapInt::from((if units < 0. { units.floor() } else { units.ceil() }) as i16)
+        };
+        Pos::new_xy(f(cells.x, X), f(cells.y, Y))
+    }
     fn distances_from_zero(self) -> Size {
         Size::new_xy_with(move |dim| self[dim].distance_from_zero())
     }
+    // where I end up after `ticks` of moving at `vel`, ignoring collisions
+    fn moved(mut self, rules: &Rules, vel: Vel, ticks: u16) -> Self {
+        let move_size = if vel[X].is_some() && vel[Y].is_some() {
+            rules.move_size_diag()
+        } else {
+            rules.move_size()
+        };
+        for dim in Dim::iter_domain() {
+            if let Some(sign) = vel[dim] {
+                self[dim] += sign * WrapInt::from(move_size[dim].wrapping_mul(ticks));
+            }
+        }
+        self
+    }
 }
@@ -0,0 +1,169 @@
+use crate::{
+    game::{Entities, Player, NUM_PLAYERS, ZERO_POS},
+    prelude::*,
+};
+
+/*
+Bit-packed encoding of an `Entities` snapshot, relative to some base snapshot the receiver has.
+Without a base, encodes relative to all-zero entities. Per entity, in order (players, teleporters):
+    1 bit  : position changed?
+    if so  : per dim, the zigzagged wrapping difference, as a nibble-varint
+    1 bit  : velocity changed?        (players only)
+    if so  : per dim, 2 bits          (players only)
+A nibble-varint is a sequence of 4-bit groups, least significant first, each followed by 1 bit
+that is set IFF more groups follow.
+*/
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    bits_in_last: u8, // invariant: < 8
+}
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    next_bit: usize,
+}
+
+///////////////////////////////////////////
+fn zero_entities(num_teleporters: usize) -> Entities {
+    Entities { players: Default::default(), teleporters: vec![ZERO_POS; num_teleporters] }
+}
+fn zigzag(x: i16) -> u16 {
+    ((x << 1) ^ (x >> 15)) as u16
+}
+fn unzigzag(z: u16) -> i16 {
+    (z >> 1) as i16 ^ -((z & 1) as i16)
+}
+fn vel_to_bits(v: Option<Sign>) -> u32 {
+    match v {
+        None => 0,
+        Some(Positive) => 1,
+        Some(Negative) => 2,
+    }
+}
+fn vel_eq(a: Vel, b: Vel) -> bool {
+    Dim::iter_domain().all(|dim| vel_to_bits(a[dim]) == vel_to_bits(b[dim]))
+}
+impl BitWriter {
+    fn write_bit(&mut self, bit: bool) {
+        if self.bits_in_last == 0 {
+            self.bytes.push(0);
+        }
+        if bit {
+            *self.bytes.last_mut().unwrap() |= 1 << self.bits_in_last;
+        }
+        self.bits_in_last = (self.bits_in_last + 1) % 8;
+    }
+    fn write_bits(&mut self, value: u32, bits: u8) {
+        for i in 0..bits {
+            self.write_bit((value >> i) & 1 != 0);
+        }
+    }
+    fn write_varint(&mut self, mut value: u16) {
+        loop {
+            self.write_bits(value as u32 & 0xF, 4);
+            value >>= 4;
+            self.write_bit(value != 0);
+            if value == 0 {
+                return;
+            }
+        }
+    }
+    fn write_pos(&mut self, base: Pos, new: Pos) {
+        let changed = base != new;
+        self.write_bit(changed);
+        if changed {
+            for dim in Dim::iter_domain() {
+                self.write_varint(zigzag((new[dim] - base[dim]).into()));
+            }
+        }
+    }
+}
+impl BitReader<'_> {
+    fn read_bit(&mut self) -> Option<bool> {
+        let byte = self.bytes.get(self.next_bit / 8)?;
+        let bit = (byte >> (self.next_bit % 8)) & 1 != 0;
+        self.next_bit += 1;
+        Some(bit)
+    }
+    fn read_bits(&mut self, bits: u8) -> Option<u32> {
+        let mut value = 0;
+        for i in 0..bits {
+            value |= (self.read_bit()? as u32) << i;
+        }
+        Some(value)
+    }
+    fn read_varint(&mut self) -> Option<u16> {
+        let mut value: u16 = 0;
+        for shift in (0..16).step_by(4) {
+            value |= (self.read_bits(4)? as u16) << shift;
+            if !self.read_bit()? {
+                return Some(value);
+            }
+        }
+        None // too many groups for a u16
+    }
+    fn read_pos(&mut self, base: Pos) -> Option<Pos> {
+        let mut pos = base;
+        if self.read_bit()? {
+            for dim in Dim::iter_domain() {
+                pos[dim] += unzigzag(self.read_varint()?);
+            }
+        }
+        Some(pos)
+    }
+    fn read_vel_dim(&mut self) -> Option<Option<Sign>> {
+        match self.read_bits(2)? {
+            0 => Some(None),
+            1 => Some(Some(Positive)),
+            2 => Some(Some(Negative)),
+            _ => None,
+        }
+    }
+}
+
+pub fn encode(base: Option<&Entities>, new: &Entities) -> Vec<u8> {
+    let zero;
+    let base = match base {
+        Some(base) => base,
+        None => {
+            zero = zero_entities(new.teleporters.len());
+            &zero
+        }
+    };
+    let mut w = BitWriter::default();
+    for (base, new) in base.players.iter().zip(new.players.iter()) {
+        w.write_pos(base.pos, new.pos);
+        let vel_changed = !vel_eq(base.vel, new.vel);
+        w.write_bit(vel_changed);
+        if vel_changed {
+            for dim in Dim::iter_domain() {
+                w.write_bits(vel_to_bits(new.vel[dim]), 2);
+            }
+        }
+    }
+    for (&base, &new) in base.teleporters.iter().zip(new.teleporters.iter()) {
+        w.write_pos(base, new);
+    }
+    w.bytes
+}
+
+// None IFF the bytes are malformed
+pub fn decode(base: Option<&Entities>, num_teleporters: usize, bytes: &[u8]) -> Option<Entities> {
+    let mut r = BitReader { bytes, next_bit: 0 };
+    let mut new = base.cloned().unwrap_or_else(|| zero_entities(num_teleporters));
+    if new.teleporters.len() != num_teleporters {
+        return None;
+    }
+    for i in 0..NUM_PLAYERS as usize {
+        let player: &mut Player = &mut new.players[i];
+        player.pos = r.read_pos(player.pos)?;
+        if r.read_bit()? {
+            player.vel = Vel::new_xy(r.read_vel_dim()?, r.read_vel_dim()?);
+        }
+    }
+    for teleporter in new.teleporters.iter_mut() {
+        *teleporter = r.read_pos(*teleporter)?;
+    }
+    Some(new)
+}
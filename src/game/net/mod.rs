@@ -0,0 +1,1720 @@
+mod delta;
+pub mod reliable;
+mod session;
+mod sim;
+pub mod stats;
+#[cfg(test)]
+mod tests;
+
+use {
+    crate::{
+        game::{
+            config::{AiKind, IfClient, IfServer, NetSimConfig},
+            discovery::{DiscoveryResponder, ServerListing},
+            room::{CELL_COUNTS, MAZE_VERSION},
+            rules::Rules,
+            Door, DoorSet, Entities, Player, PlayerArr, PlayerArrExt, PlayerColor, Room, World,
+            NUM_PLAYERS,
+        },
+        prelude::*,
+    },
+    bincode::Options,
+    fnv::FnvHasher,
+    reliable::{Event, ReliableChannel, Seq},
+    session::{PublicBytes, Session},
+    sim::Socket,
+    stats::{NetStats, StatsMeter, TICKS_PER_SEC},
+    std::{
+        borrow::Cow,
+        collections::VecDeque,
+        hash::Hasher,
+        io,
+        net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket},
+        sync::mpsc,
+        thread,
+        time::Instant,
+    },
+    x25519_dalek::EphemeralSecret,
+};
+
+/////////////////////////////////////////////////////////
+
+type Timestamp = WrapInt;
+/////////////////////////////////////////////////////////
+struct Io {
+    udp: Socket,  // nonblocking. bound. connected IFF client.
+    buf: Vec<u8>, // invariant: EMPTY if no message is prepared
+}
+
+// the most recent snapshots, oldest first. Deltas are encoded relative to these.
+type SnapshotHistory = VecDeque<(Timestamp, Entities)>;
+
+pub struct Client {
+    io: Io, // nonblocking && bound && connected
+    session: Session,
+    server_ts: Timestamp,
+    client_ts: Timestamp,
+    received: SnapshotHistory,
+    reliable: ReliableChannel,
+    // client updates since the newest snapshot arrived
+    ticks_since_newest: u16,
+    interpolation_delay_ticks: u16,
+    stats: StatsMeter,
+    server_addr: SocketAddr,
+    join: JoinSettings,
+    // None until the server first shares it
+    host_state: Option<HostState>,
+}
+// what I joined with, kept for joining a successor
+#[derive(Clone)]
+struct JoinSettings {
+    preferred_color: PlayerColor,
+    spectate: bool,
+    password: Option<String>,
+    interpolation_delay_ticks: u16,
+    net_sim: Option<NetSimConfig>,
+}
+// what a client becomes once its server is gone
+pub enum Migrated {
+    // I serve the match now, as configured, from where the server left these
+    Host(Server, IfServer, Entities, PlayerArr<DoorSet>),
+    Joined(Client, World, Option<PlayerColor>),
+}
+type Joined = (Client, World, Option<PlayerColor>);
+type JoinResult = mpsc::Receiver<Result<Joined, JoinError>>;
+// a client looking for the successor of its lost server. Polled, so that the game goes on
+pub struct Migration {
+    host_state: HostState,
+    // the next of `host_state.successors` to try
+    next_successor: usize,
+    my_color: Option<PlayerColor>,
+    join: JoinSettings,
+    // where I was. Others know me by its port
+    local_addr: SocketAddr,
+    // the newest snapshot I got, if newer than `host_state`
+    newest: Option<Entities>,
+    // the successor I am joining, on another thread
+    joining: Option<(PlayerColor, SocketAddr, JoinResult)>,
+}
+
+pub struct Server {
+    io: Io, // nonblocking && bound
+    clients: PlayerArr<Option<ServerClient>>,
+    spectators: Vec<ServerClient>,
+    // sent `StcChallenge` but not yet joined. oldest first
+    pending: VecDeque<Pending>,
+    password: Option<String>,
+    kick_after_violations: Option<u32>,
+    ai_kind: AiKind,
+    // hosts I kicked, and when. By IP, as they can rejoin from any port
+    banned: Vec<(IpAddr, Instant)>,
+    // colors whose clients I kicked or dropped since `take_freed_colors`
+    freed: Vec<PlayerColor>,
+    name: String,
+    discovery: Option<DiscoveryResponder>,
+    // when I last moved each player myself, while that is still in `sent`
+    relocated_at: PlayerArr<Option<Timestamp>>,
+    // moves doors. Reseeded whenever it is shared with clients
+    rng: Rng,
+    ticks_until_host_state: u16,
+    room_seed: u64,
+    server_ts: Timestamp,
+    sent: SnapshotHistory,
+}
+struct ServerClient {
+    addr: SocketAddr,
+    client_ts: Timestamp,
+    acked_server_ts: Option<Timestamp>,
+    reliable: ReliableChannel,
+    // grows by one per tick. Chats and pings cost `CHAT_COST` each
+    chat_credit: u16,
+    // told that a chat was dropped, and none was relayed since. They are told only once
+    chat_throttled: bool,
+    session: Session,
+    // the newest update whose position I accepted, by their clock and mine.
+    // Moves are checked against the time since then
+    accepted_client_ts: Timestamp,
+    accepted_server_ts: Timestamp,
+    // Some(ts) if I moved their player at `ts`, and they have not yet seen it
+    relocated_at: Option<Timestamp>,
+    violations: u32,
+    // what they saw of the others when they last moved
+    view_ts: Option<Timestamp>,
+    // as they declared it when they joined. At most `MAX_REWIND_TICKS`
+    interpolation_delay_ticks: i16,
+    // the door of theirs I moved last. Updates from before they went through it may be late
+    retired_door: Option<Door>,
+    stats: StatsMeter,
+    // consecutive ticks that sending to them failed, e.g. as their host reported it unreachable
+    send_failures: u16,
+    // my `server_ts` when they last sent me something I could open
+    heard_at: Timestamp,
+}
+struct Pending {
+    addr: SocketAddr,
+    client_public: PublicBytes,
+    server_public: PublicBytes,
+    session: Session,
+}
+
+// the server's state, as of `server_ts`. With it, one of the players can host the match once the
+// server is gone. Only players get it, as only they may host
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HostState {
+    server_ts: Timestamp,
+    entities: Entities,
+    doors: PlayerArr<DoorSet>,
+    room_seed: u64,
+    rng_seed: u64,
+    // played by the server or its AIs. A successor plays them with AIs of the same kind
+    ai_colors: Vec<PlayerColor>,
+    ai_kind: AiKind,
+    // the players that would host next, in order, at the addresses the server knows them by
+    successors: Vec<(PlayerColor, SocketAddr)>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct StcTimelyData<'a> {
+    entities: Cow<'a, Entities>,
+    server_ts: Timestamp,
+}
+
+// Variant order is part of the protocol! `CtsHello` and `StcReject` come first, and
+// `protocol_version` is the first field of `CtsHello`, so that any two versions understand them.
+// Only the first three are sent as they are. The rest are always inside a `Sealed`.
+#[derive(Debug, Serialize, Deserialize)]
+enum Msg<'a> {
+    CtsHello {
+        protocol_version: u16,
+        ruleset_hash: u64,
+        client_public: PublicBytes,
+    },
+    StcReject {
+        reason: RejectReason,
+    },
+    StcChallenge {
+        server_public: PublicBytes,
+    },
+    CtsJoin {
+        client_ts: Timestamp,
+        preferred_color: PlayerColor,
+        spectate: bool,
+        // how far behind the newest snapshot I show the others. Bounds the rewinds for my captures
+        interpolation_delay_ticks: u16,
+    },
+    StcHello {
+        server_ts: Timestamp,
+        room_seed: u64,
+        server_entities: Cow<'a, Entities>,
+        your_color: Option<PlayerColor>, // None for spectators
+        doors: Cow<'a, PlayerArr<DoorSet>>,
+        rules: Cow<'a, Rules>,
+    },
+    CtsUpdate {
+        player: Player,
+        client_ts: Timestamp,
+        // newest snapshot the client has decoded
+        acked_server_ts: Option<Timestamp>,
+        // the snapshot that the client's view of the others matched when it moved
+        view_ts: Option<Timestamp>,
+    },
+    StcUpdate {
+        server_ts: Timestamp,
+        // the newest `client_ts` the server has from this client, for measuring round trips
+        echo_client_ts: Timestamp,
+        // snapshot is delta-encoded relative to the snapshot of this timestamp
+        base_ts: Option<Timestamp>,
+        snapshot: Cow<'a, [u8]>,
+    },
+    Reliable {
+        first_seq: Seq,
+        events: Cow<'a, [Event]>,
+    },
+    ReliableAck {
+        next_expected_seq: Seq,
+    },
+    // a spectator's counterpart to `CtsUpdate`
+    CtsSpectate {
+        client_ts: Timestamp,
+        acked_server_ts: Option<Timestamp>,
+    },
+    // another `Msg`, encrypted and authenticated with the session key
+    Sealed {
+        counter: u64,
+        ciphertext: Cow<'a, [u8]>,
+    },
+    StcHostState {
+        state: HostState,
+    },
+    // the server is quitting. Its successor takes over
+    StcLeaving,
+    // the client is quitting. Its color is free at once
+    CtsLeaving,
+}
+
+// Variant order is part of the protocol! Append new variants only.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum RejectReason {
+    ProtocolMismatch { server_version: u16 },
+    RulesetMismatch,
+    ServerFull,
+    // from a lobby: the requested game is unknown or full
+    NoSuchGame,
+    WrongPassword,
+    // made too many impossible moves
+    Kicked,
+}
+
+// why a client could not join a game
+#[derive(Debug)]
+pub enum JoinError {
+    Unresolved { addr: String, cause: Option<io::Error> },
+    Rejected(RejectReason),
+    // LAN discovery found no server to join
+    NothingDiscovered,
+    // the server was lost, and none of its successors took over
+    NoSuccessor,
+    // the server plays by rules that would break my game
+    InvalidRules,
+    Net(NetError),
+}
+
+// why the network stopped working for me. Failures of single peers are handled, not returned
+#[derive(Debug)]
+pub enum NetError {
+    Bind { addr: SocketAddr, cause: io::Error },
+    Connect { addr: SocketAddr, cause: io::Error },
+    // the server has been silent for a while, and its host says nothing listens there
+    ServerUnreachable(io::Error),
+    ServerSilent,
+    ServerLeft,
+    // e.g. kicked
+    Rejected(RejectReason),
+    Io(io::Error),
+}
+
+// in ticks of moving
+const ACCEPTED_CLIENT_MOVES: u16 = 20;
+// clients' clocks may run ahead of mine by this much, as their updates are delayed unevenly
+const CLIENT_TS_SLACK: i16 = 8;
+// extra moves a client may make. They may be pushed out of walls, or round differently
+const CLIENT_MOVE_SLACK: u16 = 4;
+// bump whenever the meaning or serialized form of `Msg` changes
+pub const PROTOCOL_VERSION: u16 = 19;
+const SNAPSHOT_HISTORY_LEN: usize = 32;
+const MAX_SPECTATORS: usize = 8;
+const MAX_PENDING: usize = 16;
+// in bytes. A full batch of reliable events must fit in one datagram
+pub const MAX_CHAT_LEN: usize = 80;
+// each client may send a burst of 5 chats and pings, then one per second
+const CHAT_COST: u16 = 60;
+const MAX_CHAT_CREDIT: u16 = CHAT_COST * 5;
+// when snapshots are late, other players keep moving at their last known velocity for at most this long
+const MAX_EXTRAPOLATION_TICKS: u16 = 10;
+// how far back the server will look to judge captures as clients saw them
+const MAX_REWIND_TICKS: i16 = 20;
+// how much older than their declared interpolation delay a client's view may be
+const VIEW_TS_SLACK: i16 = 2;
+const HELLO_RESEND: Duration = Duration::from_millis(500);
+// joins sent with a session that the server did not answer, before I say hello again
+const MAX_UNANSWERED_JOINS: u8 = 4;
+pub const JOIN_PATIENCE: Duration = Duration::from_secs(15);
+// clients give up on a server that is unreachable and silent for this long
+const SERVER_SILENCE_TICKS: u16 = TICKS_PER_SEC * 5;
+// servers drop clients whose hosts are unreachable for this long
+const MAX_SEND_FAILURES: u16 = TICKS_PER_SEC * 5;
+// servers drop clients that are silent for this long. Most hosts never report a client gone
+const CLIENT_SILENCE_TICKS: u16 = TICKS_PER_SEC * 5;
+const HOST_STATE_TICKS: u16 = TICKS_PER_SEC;
+// how long a migrating client waits for each successor to take over
+const MIGRATION_PATIENCE: Duration = Duration::from_secs(3);
+// kicked hosts may not join again for this long
+const BAN_DURATION: Duration = Duration::from_secs(60 * 5);
+
+//////////////////////////////////////////////////////////////////////
+pub fn bincode_config() -> impl bincode::config::Options {
+    // leaves room for the seal around a 1024-byte message
+    bincode::DefaultOptions::new().with_limit(1024 + 64).with_varint_encoding()
+}
+fn find_snapshot(history: &SnapshotHistory, ts: Timestamp) -> Option<&Entities> {
+    history.iter().find(|(t, _)| *t == ts).map(|(_, entities)| entities)
+}
+fn push_snapshot(history: &mut SnapshotHistory, ts: Timestamp, entities: Entities) {
+    if history.len() == SNAPSHOT_HISTORY_LEN {
+        history.pop_front();
+    }
+    history.push_back((ts, entities));
+}
+// peers must agree on everything that affects the simulation, or they silently desync.
+// What's configurable is in `Rules` instead, which clients get from the server
+pub fn ruleset_hash() -> u64 {
+    // explicit little-endian bytes, unlike `Hash`, are the same on every platform
+    let mut h = FnvHasher::default();
+    h.write(&MAZE_VERSION.to_le_bytes());
+    for &count in CELL_COUNTS.arr.iter() {
+        h.write(&count.to_le_bytes());
+    }
+    h.finish()
+}
+// a step this small between where a client and the server have it is not corrected
+fn accepted_client_move(rules: &Rules) -> Size {
+    rules.move_size().scalar_mul(ACCEPTED_CLIENT_MOVES)
+}
+// Some(msg) IFF `session` can open the sealed message
+fn open_sealed(session: &mut Session, counter: u64, ciphertext: &[u8]) -> Option<Msg<'static>> {
+    let plaintext = session.open(counter, ciphertext)?;
+    match bincode_config().deserialize(&plaintext) {
+        Ok(Msg::Sealed { .. }) | Err(_) => None,
+        Ok(msg) => Some(msg),
+    }
+}
+// Some(protocol_version) IFF these are the bytes of a `Msg::CtsHello` of any protocol version
+fn peek_hello_version(bytes: &[u8]) -> Option<u16> {
+    const CTS_HELLO_VARIANT: u32 = 0;
+    match bincode_config().allow_trailing_bytes().deserialize::<(u32, u16)>(bytes) {
+        Ok((CTS_HELLO_VARIANT, protocol_version)) => Some(protocol_version),
+        _ => None,
+    }
+}
+impl std::fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ProtocolMismatch { server_version } => write!(
+                f,
+                "server speaks protocol version {}, but this client speaks version {}",
+                server_version, PROTOCOL_VERSION
+            ),
+            Self::RulesetMismatch => {
+                write!(f, "server was built with a different maze (generator or room size)")
+            }
+            Self::ServerFull => write!(f, "server has no free player colors"),
+            Self::NoSuchGame => write!(f, "lobby has no such game with a free player color"),
+            Self::WrongPassword => write!(f, "server has a different password"),
+            Self::Kicked => write!(f, "server kicked us for making impossible moves"),
+        }
+    }
+}
+impl std::fmt::Display for JoinError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unresolved { addr, cause } => {
+                write!(f, "could not resolve {:?}, expected \"host:port\" ", addr)?;
+                write!(f, "like \"localhost:8000\" or \"[::1]:8000\"")?;
+                match cause {
+                    Some(cause) => write!(f, " ({})", cause),
+                    None => write!(f, " (no addresses)"),
+                }
+            }
+            Self::Rejected(reason) => write!(f, "the server rejected us: {}", reason),
+            Self::NothingDiscovered => write!(f, "no joinable server answered on the LAN"),
+            Self::NoSuccessor => write!(f, "no successor took over the game"),
+            Self::InvalidRules => write!(f, "the server's rules are invalid"),
+            Self::Net(e) => e.fmt(f),
+        }
+    }
+}
+impl std::fmt::Display for NetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Bind { addr, cause } => write!(f, "could not bind to {:?} ({})", addr, cause),
+            Self::Connect { addr, cause } => {
+                write!(f, "could not connect to {:?} ({})", addr, cause)
+            }
+            Self::ServerUnreachable(cause) => {
+                write!(f, "lost the server, which seems to be down ({})", cause)
+            }
+            Self::ServerSilent => write!(f, "the server went silent"),
+            Self::ServerLeft => write!(f, "the server left"),
+            Self::Rejected(reason) => write!(f, "the server rejected us: {}", reason),
+            Self::Io(cause) => write!(f, "network failure ({})", cause),
+        }
+    }
+}
+impl NetError {
+    // true IFF the server is gone, but a successor may take over
+    pub fn lost_server(&self) -> bool {
+        matches!(self, Self::ServerUnreachable(_) | Self::ServerSilent | Self::ServerLeft)
+    }
+}
+impl From<RejectReason> for JoinError {
+    fn from(reason: RejectReason) -> Self {
+        Self::Rejected(reason)
+    }
+}
+impl From<NetError> for JoinError {
+    fn from(e: NetError) -> Self {
+        Self::Net(e)
+    }
+}
+impl From<io::Error> for NetError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+// a peer's host reported (with ICMP) that nothing listens there. Other peers are unaffected
+pub fn is_peer_error(e: &io::Error) -> bool {
+    use io::ErrorKind as Ek;
+    matches!(e.kind(), Ek::ConnectionRefused | Ek::ConnectionReset | Ek::ConnectionAborted)
+}
+// nothing to receive, or no room to send. As if a datagram were lost
+pub fn is_would_block(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+// the first address that "host:port" resolves to
+pub fn resolve(addr: &str) -> Result<SocketAddr, JoinError> {
+    let unresolved = |cause| JoinError::Unresolved { addr: addr.to_owned(), cause };
+    addr.to_socket_addrs().map_err(|e| unresolved(Some(e)))?.next().ok_or_else(|| unresolved(None))
+}
+// loopback, including IPv4 loopback mapped into IPv6
+fn is_loopback(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => v4.is_loopback(),
+        IpAddr::V6(v6) => v6.is_loopback() || v6.to_ipv4().map_or(false, |v4| v4.is_loopback()),
+    }
+}
+// for binding a socket that can reach `addr`, on a port chosen by the OS
+pub fn unspecified_like(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    }
+}
+// like `UdpSocket::bind`, but the IPv6 wildcard address accepts IPv4 too, on every platform
+pub fn bind_udp(addr: SocketAddr) -> io::Result<UdpSocket> {
+    match addr {
+        SocketAddr::V6(v6) if v6.ip().is_unspecified() => {
+            let socket =
+                socket2::Socket::new(socket2::Domain::ipv6(), socket2::Type::dgram(), None)?;
+            socket.set_only_v6(false)?;
+            socket.bind(&addr.into())?;
+            Ok(socket.into_udp_socket())
+        }
+        _ => UdpSocket::bind(addr),
+    }
+}
+impl Io {
+    const BUF_CAP: usize = 2048;
+    pub fn new(addr: SocketAddr, net_sim: Option<&NetSimConfig>) -> Result<Self, NetError> {
+        let udp = Socket::bind(addr, net_sim).map_err(|cause| NetError::Bind { addr, cause })?;
+        Ok(Self { udp, buf: Vec::with_capacity(Self::BUF_CAP) })
+    }
+    pub fn nonblocking(mut self) -> Result<Self, NetError> {
+        self.udp.set_nonblocking(true)?;
+        Ok(self)
+    }
+    pub fn connected(self, addr: SocketAddr) -> Result<Self, NetError> {
+        self.udp.connect(addr).map_err(|cause| NetError::Connect { addr, cause })?;
+        Ok(self)
+    }
+    // `func` sends the staged bytes, returning how many were sent
+    pub fn with_staged_msg(
+        &mut self,
+        msg: &Msg,
+        func: impl FnOnce(&mut [u8], &mut Socket) -> io::Result<usize>,
+    ) -> io::Result<usize> {
+        bincode_config().serialize_into(&mut self.buf, msg).unwrap();
+        let res = func(self.buf.as_mut_slice(), &mut self.udp);
+        self.buf.clear();
+        res
+    }
+    // like `with_staged_msg`, but `msg` is sealed inside a `Msg::Sealed`
+    fn with_sealed_msg(
+        &mut self,
+        session: &mut Session,
+        msg: &Msg,
+        func: impl FnOnce(&mut [u8], &mut Socket) -> io::Result<usize>,
+    ) -> io::Result<usize> {
+        bincode_config().serialize_into(&mut self.buf, msg).unwrap();
+        let (counter, ciphertext) = session.seal(&self.buf);
+        self.buf.clear();
+        self.with_staged_msg(&Msg::Sealed { counter, ciphertext: Cow::Owned(ciphertext) }, func)
+    }
+    // sends whatever `reliable` needs sent this tick. To `dest` if given, else to the connected
+    // peer. Returns the number of bytes sent
+    fn send_reliable(
+        &mut self,
+        reliable: &mut ReliableChannel,
+        session: &mut Session,
+        dest: Option<SocketAddr>,
+    ) -> io::Result<usize> {
+        let send = |bytes: &mut [u8], udp: &mut Socket| match dest {
+            Some(addr) => udp.send_to(bytes, addr),
+            None => udp.send(bytes),
+        };
+        let mut sent = 0;
+        if let Some((first_seq, events)) = reliable.to_send() {
+            sent += self.with_sealed_msg(session, &Msg::Reliable { first_seq, events }, send)?;
+        }
+        if let Some(next_expected_seq) = reliable.take_ack() {
+            sent += self.with_sealed_msg(session, &Msg::ReliableAck { next_expected_seq }, send)?;
+        }
+        Ok(sent)
+    }
+    pub fn with_temp_cap_buf<R>(&mut self, func: impl FnOnce(&mut [u8], &mut Socket) -> R) -> R {
+        unsafe {
+            // SAFE! u8 vector contents are P.O.D. with no invalid repr
+            self.buf.set_len(Self::BUF_CAP);
+        }
+        let res = func(&mut self.buf, &mut self.udp);
+        self.buf.clear();
+        res
+    }
+}
+impl Client {
+    // the next message from the server, and whether it was sealed
+    fn recv(
+        io: &mut Io,
+        mut session: Option<&mut Session>,
+        stats: &mut StatsMeter,
+    ) -> io::Result<Option<(Msg<'static>, bool)>> {
+        io.with_temp_cap_buf(|temp_buf, udp| loop {
+            let n = match udp.recv(temp_buf) {
+                Ok(0) => return Ok(None),
+                Ok(n) => n,
+                Err(e) if is_would_block(&e) => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            stats.on_recv(n);
+            match bincode_config().deserialize::<Msg>(&temp_buf[..n]) {
+                Ok(Msg::Sealed { counter, ciphertext }) => {
+                    if let Some(session) = &mut session {
+                        if let Some(msg) = open_sealed(session, counter, &ciphertext) {
+                            return Ok(Some((msg, true)));
+                        }
+                    }
+                }
+                Ok(Msg::StcChallenge { server_public }) => {
+                    return Ok(Some((Msg::StcChallenge { server_public }, false)))
+                }
+                Ok(Msg::StcReject { reason }) => {
+                    return Ok(Some((Msg::StcReject { reason }, false)))
+                }
+                Ok(_) | Err(_) => {} // garbage, or should have been sealed
+            }
+        })
+    }
+    pub fn new(
+        config: &IfClient,
+        server_addr: SocketAddr,
+        net_sim: Option<&NetSimConfig>,
+    ) -> Result<(Self, World, Option<PlayerColor>), JoinError> {
+        let join = JoinSettings {
+            preferred_color: config.preferred_color,
+            spectate: config.spectate,
+            password: config.password.clone(),
+            interpolation_delay_ticks: config.interpolation_delay_ticks,
+            net_sim: net_sim.cloned(),
+        };
+        Self::join(join, server_addr, JOIN_PATIENCE)
+    }
+    // a hello with new keys, and the secret to make a session with its reply
+    fn new_hello() -> (Option<EphemeralSecret>, Msg<'static>) {
+        let (my_secret, client_public) = session::new_key_pair();
+        let hello = Msg::CtsHello {
+            protocol_version: PROTOCOL_VERSION,
+            ruleset_hash: ruleset_hash(),
+            client_public,
+        };
+        (Some(my_secret), hello)
+    }
+    // gives up if the server does not let me in within `patience`
+    fn join(
+        join: JoinSettings,
+        server_addr: SocketAddr,
+        patience: Duration,
+    ) -> Result<(Self, World, Option<PlayerColor>), JoinError> {
+        let started = Instant::now();
+        let mut io = Io::new(unspecified_like(server_addr), join.net_sim.as_ref())?
+            .connected(server_addr)?;
+        // re-send hello periodically, in case it (or the reply) is lost
+        io.udp.set_read_timeout(Some(HELLO_RESEND)).map_err(NetError::Io)?;
+        let client_ts = Timestamp::default();
+        let (mut my_secret, mut hello) = Self::new_hello();
+        let mut session: Option<Session> = None;
+        let mut unanswered_joins = 0;
+        // anyone can send a reject that isn't sealed. Once I have a session, those only explain
+        // why I gave up
+        let mut unsealed_reject = None;
+        let mut stats = StatsMeter::default();
+        let join_msg = Msg::CtsJoin {
+            preferred_color: join.preferred_color,
+            client_ts,
+            spectate: join.spectate,
+            interpolation_delay_ticks: join.interpolation_delay_ticks,
+        };
+        loop {
+            if started.elapsed() >= patience {
+                return Err(match unsealed_reject {
+                    Some(reason) => JoinError::Rejected(reason),
+                    None => NetError::ServerSilent.into(),
+                });
+            }
+            if unanswered_joins == MAX_UNANSWERED_JOINS {
+                // the server forgot the challenge it sent me, or restarted. Start over
+                session = None;
+                unanswered_joins = 0;
+                let (secret, new_hello) = Self::new_hello();
+                my_secret = secret;
+                hello = new_hello;
+            }
+            let sent = match &mut session {
+                None => io.with_staged_msg(&hello, |bytes, udp| udp.send(bytes)),
+                Some(session) => {
+                    io.with_sealed_msg(session, &join_msg, |bytes, udp| udp.send(bytes))
+                }
+            };
+            let received = sent.and_then(|sent| {
+                stats.on_sent(sent);
+                Self::recv(&mut io, session.as_mut(), &mut stats)
+            });
+            let received = match received {
+                Ok(received) => received,
+                Err(e) if is_peer_error(&e) => {
+                    // the server may not be up yet. Keep trying
+                    std::thread::sleep(HELLO_RESEND);
+                    None
+                }
+                Err(e) => return Err(NetError::Io(e).into()),
+            };
+            match &received {
+                None if session.is_some() => unanswered_joins += 1,
+                // they know my session, so they will let me in once a join gets through
+                Some((_, true)) => unanswered_joins = 0,
+                _ => {}
+            }
+            match received {
+                Some((Msg::StcChallenge { server_public }, _)) => {
+                    if let Some(my_secret) = my_secret.take() {
+                        let password = join.password.as_deref();
+                        session = Some(Session::new(my_secret, server_public, password, false));
+                    }
+                }
+                Some((
+                    Msg::StcHello {
+                        server_entities,
+                        server_ts,
+                        your_color,
+                        room_seed,
+                        doors,
+                        rules,
+                    },
+                    true,
+                )) => {
+                    let entities = server_entities.into_owned();
+                    let doors = doors.into_owned();
+                    let as_ruled = entities.teleporters.len() == rules.num_teleporters as usize
+                        && doors.iter().all(|d| d.len() == rules.num_my_doors as usize);
+                    if !(rules.is_valid() && as_ruled) {
+                        return Err(JoinError::InvalidRules);
+                    }
+                    let (room, _rng) = Room::new_seeded(room_seed);
+                    let rules = rules.into_owned();
+                    let world = World { room, entities, doors, rules };
+                    let me = Self {
+                        io: io.nonblocking()?,
+                        session: session.unwrap(),
+                        server_ts,
+                        client_ts,
+                        received: Default::default(),
+                        reliable: Default::default(),
+                        ticks_since_newest: 0,
+                        interpolation_delay_ticks: join.interpolation_delay_ticks,
+                        stats,
+                        server_addr,
+                        join,
+                        host_state: None,
+                    };
+                    return Ok((me, world, your_color));
+                }
+                Some((Msg::StcReject { reason }, sealed)) if sealed || session.is_none() => {
+                    return Err(reason.into())
+                }
+                Some((Msg::StcReject { reason }, _)) => unsealed_reject = Some(reason),
+                _ => {}
+            }
+        }
+    }
+    // one report that the server is unreachable is not fatal. It may be restarting.
+    // But its silence for long is
+    fn check_io<T: Default>(&self, res: io::Result<T>) -> Result<T, NetError> {
+        match res {
+            Ok(t) => Ok(t),
+            Err(e) if is_would_block(&e) => Ok(T::default()),
+            Err(e) if !is_peer_error(&e) => Err(NetError::Io(e)),
+            Err(e) if self.ticks_since_newest >= SERVER_SILENCE_TICKS => {
+                Err(NetError::ServerUnreachable(e))
+            }
+            Err(_) => Ok(T::default()),
+        }
+    }
+    // `my_color` is None if I am spectating. Err if the server kicked me, or is unreachable
+    pub fn update(
+        &mut self,
+        my_color: Option<PlayerColor>,
+        rules: &Rules,
+        entities: &mut Entities,
+        mut event_callback: impl FnMut(Event),
+    ) -> Result<(), NetError> {
+        // handle all incoming server messages in the correct order
+        loop {
+            let received = Self::recv(&mut self.io, Some(&mut self.session), &mut self.stats);
+            let (msg, sealed) = match self.check_io(received)? {
+                Some(received) => received,
+                None => break,
+            };
+            let (server_ts, base_ts, snapshot) = match msg {
+                _ if !sealed => continue,
+                Msg::StcUpdate { server_ts, echo_client_ts, base_ts, snapshot } => {
+                    self.stats.on_update(server_ts);
+                    self.stats.on_rtt_sample((self.client_ts - echo_client_ts).into());
+                    (server_ts, base_ts, snapshot)
+                }
+                Msg::Reliable { first_seq, events } => {
+                    self.reliable.on_recv(first_seq, events).for_each(&mut event_callback);
+                    continue;
+                }
+                Msg::ReliableAck { next_expected_seq } => {
+                    self.reliable.on_ack(next_expected_seq);
+                    continue;
+                }
+                Msg::StcReject { reason } => return Err(NetError::Rejected(reason)),
+                Msg::StcHostState { state } => {
+                    self.host_state = Some(self.reachable(state));
+                    continue;
+                }
+                Msg::StcLeaving => return Err(NetError::ServerLeft),
+                _ => continue,
+            };
+            if self.server_ts < server_ts {
+                let base = match base_ts {
+                    Some(base_ts) => match find_snapshot(&self.received, base_ts) {
+                        Some(base) => Some(base),
+                        None => continue, // I forgot the base! Cannot decode
+                    },
+                    None => None,
+                };
+                let num_teleporters = rules.num_teleporters as usize;
+                let server_entities = match delta::decode(base, num_teleporters, &snapshot) {
+                    Some(server_entities) => server_entities,
+                    None => continue,
+                };
+                // new info!
+                self.server_ts = server_ts;
+                self.ticks_since_newest = 0;
+                push_snapshot(&mut self.received, server_ts, server_entities.clone());
+                // overwrite all entity data except my own
+                let my_old = my_color.map(|color| (color, entities.players[color].clone()));
+                *entities = server_entities;
+                if let Some((my_color, my_old)) = my_old {
+                    let my_new = &mut entities.players[my_color];
+                    // ... but not my velocity (mine is always accurate)
+                    my_new.vel = my_old.vel;
+                    // client ignores updates representing SMALL STEPS
+                    let diff = my_old.pos - my_new.pos;
+                    if diff.distances_from_zero() < accepted_client_move(rules) {
+                        // the difference was a small step. RESTORE what I had before
+                        my_new.pos = my_old.pos;
+                    }
+                }
+            }
+        }
+        if self.ticks_since_newest >= SERVER_SILENCE_TICKS {
+            return Err(NetError::ServerSilent);
+        }
+        let view_ts = self.interpolate_others(my_color, rules, entities);
+        self.ticks_since_newest = self.ticks_since_newest.saturating_add(1);
+        // update the server!
+        let acked_server_ts = self.received.back().map(|&(ts, _)| ts);
+        let update_msg = match my_color {
+            Some(my_color) => Msg::CtsUpdate {
+                client_ts: self.client_ts,
+                player: entities.players[my_color].clone(),
+                acked_server_ts,
+                view_ts,
+            },
+            None => Msg::CtsSpectate { client_ts: self.client_ts, acked_server_ts },
+        };
+        let sent =
+            self.io.with_sealed_msg(&mut self.session, &update_msg, |bytes, udp| udp.send(bytes));
+        let sent = self.check_io(sent)?;
+        self.stats.on_sent(sent);
+        let sent = self.io.send_reliable(&mut self.reliable, &mut self.session, None);
+        let sent = self.check_io(sent)?;
+        self.stats.on_sent(sent);
+        self.stats.set_snapshot_age(self.ticks_since_newest);
+        self.stats.tick();
+        self.client_ts += 1u16;
+        Ok(())
+    }
+}
+impl Client {
+    // successors on the server's own host are known to it by a loopback address.
+    // I reach them where I reach the server
+    fn reachable(&self, mut state: HostState) -> HostState {
+        for (_, addr) in state.successors.iter_mut() {
+            if is_loopback(addr.ip()) {
+                addr.set_ip(self.server_addr.ip());
+            }
+        }
+        state
+    }
+    // once the server is gone: starts looking for its successor. I am no longer usable
+    pub fn migrate(&mut self, my_color: Option<PlayerColor>) -> Result<Migration, JoinError> {
+        let host_state = self.host_state.take().ok_or(JoinError::NoSuccessor)?;
+        let local_addr = self.io.udp.local_addr().map_err(NetError::Io)?;
+        // others know me by my port. Free it, in case I serve on it
+        self.io = Io::new(unspecified_like(local_addr), None)?;
+        let newest = self
+            .received
+            .back()
+            .filter(|(ts, _)| *ts > host_state.server_ts)
+            .map(|(_, entities)| entities.clone());
+        let mut join = self.join.clone();
+        join.preferred_color = my_color.unwrap_or(join.preferred_color);
+        Ok(Migration {
+            host_state,
+            next_successor: 0,
+            my_color,
+            join,
+            local_addr,
+            newest,
+            joining: None,
+        })
+    }
+    // tells the server that my color is free, rather than letting it wait for me to go silent.
+    // Best effort. If it is lost, the server drops me once I am silent for long
+    pub fn leave(&mut self) {
+        let _ = self
+            .io
+            .with_sealed_msg(&mut self.session, &Msg::CtsLeaving, |bytes, udp| udp.send(bytes));
+    }
+    pub fn stats(&self) -> NetStats {
+        self.stats.summary()
+    }
+    // the server relays it to everyone (including me) if it is acceptable
+    pub fn send_event(&mut self, event: Event) {
+        self.reliable.push(event);
+    }
+    // sets the positions of the other players to where they were `interpolation_delay_ticks` ago,
+    // according to the snapshots either side of that moment. Returns the timestamp of that moment
+    fn interpolate_others(
+        &self,
+        my_color: Option<PlayerColor>,
+        rules: &Rules,
+        entities: &mut Entities,
+    ) -> Option<Timestamp> {
+        let (newest_ts, newest) = match self.received.back() {
+            Some((ts, newest)) => (*ts, newest),
+            None => return None,
+        };
+        // how far behind the newest snapshot do I want to be? negative when it is late
+        let behind: i16 = self.interpolation_delay_ticks as i16 - self.ticks_since_newest as i16;
+        let render_ts = newest_ts - behind;
+        let after_idx = self.received.iter().position(|(ts, _)| *ts > render_ts);
+        for color in PlayerColor::iter_domain().filter(|&color| Some(color) != my_color) {
+            let player = &mut entities.players[color];
+            player.pos = match after_idx {
+                None => {
+                    // no snapshot is new enough. extrapolate from the newest
+                    let late = ((-behind).max(0) as u16).min(MAX_EXTRAPOLATION_TICKS);
+                    let newest_player = &newest.players[color];
+                    newest_player.pos.moved(rules, newest_player.vel, late)
+                }
+                Some(0) => self.received[0].1.players[color].pos, // too old to interpolate
+                Some(i) => {
+                    let [(a_ts, a), (b_ts, b)] = [&self.received[i - 1], &self.received[i]];
+                    let [a_pos, b_pos] = [a.players[color].pos, b.players[color].pos];
+                    let small_step =
+                        (b_pos - a_pos).distances_from_zero() < accepted_client_move(rules);
+                    if !small_step {
+                        // teleported or captured in between. Snap there once it's due, instead
+                        // of sliding across the room
+                        a_pos
+                    } else {
+                        let t_span: i16 = (*b_ts - *a_ts).into();
+                        let t_into: i16 = (render_ts - *a_ts).into();
+                        let frac = t_into as f32 / t_span as f32;
+                        Pos::new_xy_with(|dim| {
+                            let diff: i16 = (b_pos[dim] - a_pos[dim]).into();
+                            a_pos[dim] + (diff as f32 * frac) as i16
+                        })
+                    }
+                }
+            };
+        }
+        Some(match after_idx {
+            None => newest_ts,
+            Some(0) => self.received[0].0,
+            Some(_) => render_ts,
+        })
+    }
+}
+impl Migration {
+    // Some once I joined the first of the successors that took over in time, or host the match
+    // myself as it is my turn. Everyone tries them in the same order
+    pub fn poll(&mut self) -> Result<Option<Migrated>, JoinError> {
+        loop {
+            if let Some((color, addr, receiver)) = &self.joining {
+                match receiver.try_recv() {
+                    Err(mpsc::TryRecvError::Empty) => return Ok(None),
+                    Ok(Ok((client, world, color))) => {
+                        return Ok(Some(Migrated::Joined(client, world, color)))
+                    }
+                    Ok(Err(e)) => println!("{:?} at {:?} did not take over: {}", color, addr, e),
+                    Err(mpsc::TryRecvError::Disconnected) => {
+                        println!("Joining {:?} at {:?} failed", color, addr)
+                    }
+                }
+                self.joining = None;
+            }
+            let host_state = &self.host_state;
+            let &(color, addr) =
+                host_state.successors.get(self.next_successor).ok_or(JoinError::NoSuccessor)?;
+            self.next_successor += 1;
+            if Some(color) == self.my_color {
+                let config = IfServer {
+                    server_addr: self.local_addr,
+                    player_color: color,
+                    room_seed: Some(host_state.room_seed),
+                    ai_enabled: host_state.ai_colors.clone(),
+                    ai_kind: host_state.ai_kind,
+                    bots: vec![],
+                    password: self.join.password.clone(),
+                    name: IfServer::default_name(),
+                    discovery_addr: None,
+                    kick_after_violations: None,
+                };
+                let rng = Rng::new_seeded(host_state.rng_seed);
+                let net_sim = self.join.net_sim.as_ref();
+                let server = Server::serve(&config, net_sim, host_state.room_seed, rng)?;
+                println!("Hosting the game at {:?}", self.local_addr);
+                let entities = self.newest.take().unwrap_or_else(|| host_state.entities.clone());
+                let doors = host_state.doors.clone();
+                return Ok(Some(Migrated::Host(server, config, entities, doors)));
+            }
+            let (sender, receiver) = mpsc::channel();
+            let join = self.join.clone();
+            thread::spawn(move || {
+                let _ = sender.send(Client::join(join, addr, MIGRATION_PATIENCE));
+            });
+            self.joining = Some((color, addr, receiver));
+        }
+    }
+}
+impl ServerClient {
+    fn new(
+        addr: SocketAddr,
+        client_ts: Timestamp,
+        server_ts: Timestamp,
+        session: Session,
+        interpolation_delay_ticks: u16,
+    ) -> Self {
+        Self {
+            addr,
+            client_ts,
+            acked_server_ts: None,
+            reliable: Default::default(),
+            chat_credit: MAX_CHAT_CREDIT,
+            chat_throttled: false,
+            session,
+            accepted_client_ts: client_ts,
+            accepted_server_ts: server_ts,
+            relocated_at: None,
+            violations: 0,
+            view_ts: None,
+            interpolation_delay_ticks: interpolation_delay_ticks.min(MAX_REWIND_TICKS as u16)
+                as i16,
+            retired_door: None,
+            stats: Default::default(),
+            send_failures: 0,
+            heard_at: server_ts,
+        }
+    }
+    // unreachable or silent for long. Most likely gone without a word
+    fn is_lost(&self, server_ts: Timestamp) -> bool {
+        let silent: i16 = (server_ts - self.heard_at).into();
+        self.send_failures >= MAX_SEND_FAILURES || silent >= CLIENT_SILENCE_TICKS as i16
+    }
+    // what they claim to have seen, moved to within their declared delay behind the newest
+    // snapshot they had. Older views would let them capture where prey no longer was
+    fn bounded_view_ts(
+        &self,
+        view_ts: Option<Timestamp>,
+        acked_server_ts: Option<Timestamp>,
+    ) -> Option<Timestamp> {
+        let (view_ts, acked) = (view_ts?, acked_server_ts?);
+        let behind: i16 = (acked - view_ts).into();
+        Some(acked - behind.clamp(0, self.interpolation_delay_ticks + VIEW_TS_SLACK))
+    }
+    fn on_acked(&mut self, server_ts: Timestamp, acked_server_ts: Option<Timestamp>) {
+        if let Some(acked) = acked_server_ts {
+            let age: i16 = (server_ts - acked).into();
+            self.stats.on_rtt_sample(age);
+            self.stats.set_snapshot_age(age.max(0) as u16);
+        }
+    }
+    // true IFF their player could have moved `from` -> `to` since the last move I accepted.
+    // Their clock may not run faster than mine (give or take some jitter)
+    fn move_is_plausible(
+        &self,
+        move_size: Size,
+        from: Pos,
+        to: Pos,
+        client_ts: Timestamp,
+        server_ts: Timestamp,
+    ) -> bool {
+        let client_ticks: i16 = (client_ts - self.accepted_client_ts).into();
+        let server_ticks: i16 = (server_ts - self.accepted_server_ts).into();
+        let ticks = client_ticks.min(server_ticks.saturating_add(CLIENT_TS_SLACK)).max(0) as u32;
+        let distances = (to - from).distances_from_zero();
+        Dim::iter_domain().all(|dim| {
+            let allowed = move_size[dim] as u32 * (ticks + CLIENT_MOVE_SLACK as u32);
+            distances[dim] as u32 <= allowed
+        })
+    }
+    // Some(event) if this client may send it now, with its sender corrected to `from`
+    fn vetted(&mut self, event: Event, from: Option<PlayerColor>) -> Option<Event> {
+        let event = match event {
+            // only the server decides these. Used doors were handled already
+            Event::Captured { .. }
+            | Event::DoorMoved { .. }
+            | Event::DoorUsed { .. }
+            | Event::ChatDropped => return None,
+            Event::Chat { mut text, .. } => {
+                while text.len() > MAX_CHAT_LEN {
+                    text.pop();
+                }
+                Event::Chat { from, text }
+            }
+            Event::Ping { kind, pos, .. } => Event::Ping { from, kind, pos },
+        };
+        match self.chat_credit.checked_sub(CHAT_COST) {
+            Some(credit) => {
+                self.chat_credit = credit;
+                self.chat_throttled = false;
+                Some(event)
+            }
+            None => {
+                // I acked it, so they think it went through
+                if !self.chat_throttled {
+                    self.chat_throttled = true;
+                    self.reliable.push(Event::ChatDropped);
+                }
+                None
+            }
+        }
+    }
+}
+impl Server {
+    pub fn new(
+        config: &IfServer,
+        rules: &Rules,
+        net_sim: Option<&NetSimConfig>,
+    ) -> Result<(Self, World, PlayerColor), NetError> {
+        let room_seed = config.room_seed.unwrap_or_else(Rng::random_seed);
+        let (room, mut rng) = Room::new_seeded(room_seed);
+        let entities = Entities::random(&mut rng, rules); // doesn't matter if local seed
+        let doors = PlayerArr::new_with(|_| room.random_new_my_doors(&mut rng, rules));
+        let world = World { room, entities, doors, rules: rules.clone() };
+        Ok((Self::serve(config, net_sim, room_seed, rng)?, world, config.player_color))
+    }
+    // serves the room of `room_seed`. Also used by successors, to take over
+    fn serve(
+        config: &IfServer,
+        net_sim: Option<&NetSimConfig>,
+        room_seed: u64,
+        rng: Rng,
+    ) -> Result<Self, NetError> {
+        Ok(Self {
+            io: Io::new(config.server_addr, net_sim)?.nonblocking()?,
+            room_seed,
+            clients: Default::default(),
+            spectators: vec![],
+            pending: Default::default(),
+            password: config.password.clone(),
+            kick_after_violations: config.kick_after_violations,
+            ai_kind: config.ai_kind,
+            banned: vec![],
+            freed: vec![],
+            name: config.name.clone(),
+            discovery: config.discovery_addr.and_then(|addr| {
+                match DiscoveryResponder::bind(addr) {
+                    Ok(discovery) => Some(discovery),
+                    Err(e) => {
+                        println!("LAN discovery is off. Failed to bind {:?}: {}", addr, e);
+                        None
+                    }
+                }
+            }),
+            relocated_at: Default::default(),
+            rng,
+            ticks_until_host_state: 0,
+            server_ts: Timestamp::default(),
+            sent: Default::default(),
+        })
+    }
+    // the next message, its sender, and whether it was sealed with the sender's session.
+    // Errors caused by single peers are skipped
+    fn recv_from(&mut self) -> Result<Option<(Msg<'static>, SocketAddr, bool)>, NetError> {
+        let Self { io, clients, spectators, pending, server_ts, .. } = self;
+        io.with_temp_cap_buf(|temp_buf, udp| loop {
+            match udp.recv_from(temp_buf) {
+                Ok((0, _)) => return Ok(None),
+                Err(e) if is_would_block(&e) => return Ok(None),
+                Err(e) if is_peer_error(&e) => {}
+                Err(e) => return Err(e.into()),
+                Ok((n, addr)) => match bincode_config().deserialize::<Msg>(&temp_buf[..n]) {
+                    Ok(Msg::Sealed { counter, ciphertext }) => {
+                        let client = clients
+                            .iter_mut()
+                            .flatten()
+                            .chain(spectators.iter_mut())
+                            .find(|c| c.addr == addr);
+                        if let Some(client) = client {
+                            client.stats.on_recv(n);
+                            if let Some(msg) =
+                                open_sealed(&mut client.session, counter, &ciphertext)
+                            {
+                                client.heard_at = *server_ts;
+                                return Ok(Some((msg, addr, true)));
+                            }
+                        } else if let Some(p) = pending.iter_mut().find(|p| p.addr == addr) {
+                            match open_sealed(&mut p.session, counter, &ciphertext) {
+                                Some(msg) => return Ok(Some((msg, addr, true))),
+                                // they derived another key, most likely from another password
+                                None => {
+                                    Self::send_reject_to(udp, RejectReason::WrongPassword, addr)
+                                }
+                            }
+                        }
+                    }
+                    Ok(Msg::CtsHello { protocol_version, ruleset_hash, client_public }) => {
+                        let msg = Msg::CtsHello { protocol_version, ruleset_hash, client_public };
+                        return Ok(Some((msg, addr, false)));
+                    }
+                    Ok(_) => {} // should have been sealed
+                    Err(_) => {
+                        // maybe a hello we cannot understand? Tell them why!
+                        if peek_hello_version(&temp_buf[..n]).is_some() {
+                            let reason =
+                                RejectReason::ProtocolMismatch { server_version: PROTOCOL_VERSION };
+                            Self::send_reject_to(udp, reason, addr);
+                        }
+                    }
+                },
+            }
+        })
+    }
+    pub fn local_addr(&self) -> SocketAddr {
+        self.io.udp.local_addr().expect("bound socket has no addr")
+    }
+    pub fn room_seed(&self) -> u64 {
+        self.room_seed
+    }
+    pub fn num_clients(&self) -> usize {
+        self.clients.iter().flatten().count()
+    }
+    // per client: their color (None for spectators), address, and stats
+    pub fn stats(&self) -> impl Iterator<Item = (Option<PlayerColor>, SocketAddr, NetStats)> + '_ {
+        let players = self
+            .clients
+            .iter()
+            .zip(PlayerColor::iter_domain())
+            .filter_map(|(client, color)| Some((Some(color), client.as_ref()?)));
+        let spectators = self.spectators.iter().map(|spectator| (None, spectator));
+        players
+            .chain(spectators)
+            .map(|(color, client)| (color, client.addr, client.stats.summary()))
+    }
+    fn respond_to_discovery(&mut self, my_color: Option<PlayerColor>, rules: &Rules) {
+        let game_port = self.local_addr().port();
+        let Self { discovery, clients, name, password, .. } = self;
+        if let Some(discovery) = discovery {
+            discovery.respond(|| {
+                let free_colors: Vec<PlayerColor> = PlayerColor::iter_domain()
+                    .filter(|&color| Some(color) != my_color && clients[color].is_none())
+                    .collect();
+                ServerListing {
+                    name: name.clone(),
+                    game_port,
+                    protocol_version: PROTOCOL_VERSION,
+                    ruleset_hash: ruleset_hash(),
+                    num_players: NUM_PLAYERS as u8 - free_colors.len() as u8,
+                    free_colors,
+                    password_required: password.is_some(),
+                    rules: rules.clone(),
+                }
+            });
+        }
+    }
+    fn client_by_addr_mut(&mut self, addr: SocketAddr) -> Option<&mut ServerClient> {
+        let Self { clients, spectators, .. } = self;
+        clients.iter_mut().flatten().chain(spectators.iter_mut()).find(|c| c.addr == addr)
+    }
+    fn take_pending(&mut self, addr: SocketAddr) -> Option<Session> {
+        let i = self.pending.iter().position(|p| p.addr == addr)?;
+        self.pending.remove(i).map(|p| p.session)
+    }
+    // the first step of the handshake. Remembers a session for them to join with
+    fn send_challenge_to(&mut self, addr: SocketAddr, client_public: PublicBytes) {
+        if self.client_by_addr_mut(addr).is_some() {
+            // already joined. A new key from the same address would be an impostor
+            return;
+        }
+        let known = self.pending.iter().position(|p| p.addr == addr);
+        let server_public = match known.map(|i| &self.pending[i]) {
+            Some(p) if p.client_public == client_public => p.server_public, // a resent hello
+            _ => {
+                if let Some(i) = known {
+                    self.pending.remove(i);
+                }
+                if self.pending.len() == MAX_PENDING {
+                    self.pending.pop_front();
+                }
+                let (server_secret, server_public) = session::new_key_pair();
+                let password = self.password.as_deref();
+                let session = Session::new(server_secret, client_public, password, true);
+                self.pending.push_back(Pending { addr, client_public, server_public, session });
+                server_public
+            }
+        };
+        // best effort, like all replies to handshakes. They will ask again
+        let msg = Msg::StcChallenge { server_public };
+        let _ = self.io.with_staged_msg(&msg, |bytes, udp| udp.send_to(bytes, addr));
+    }
+    // I moved their player myself. Until they see that, I ignore where they say they are
+    pub fn on_relocated(&mut self, color: PlayerColor) {
+        let server_ts = self.server_ts;
+        self.relocated_at[color] = Some(server_ts);
+        if let Some(client) = &mut self.clients[color] {
+            client.relocated_at = Some(server_ts);
+        }
+    }
+    // where `viewer`'s client saw `seen` when it last moved, if that is recent enough to trust.
+    // Lets captures be judged as the predator saw them, despite latency
+    pub fn seen_pos(&self, viewer: PlayerColor, seen: PlayerColor) -> Option<Pos> {
+        let view_ts = self.clients[viewer].as_ref()?.view_ts?;
+        let rewind: i16 = (self.server_ts - view_ts).into();
+        if !(0..=MAX_REWIND_TICKS).contains(&rewind) {
+            return None;
+        }
+        if let Some(relocated_at) = self.relocated_at[seen] {
+            if view_ts < relocated_at {
+                return None; // they saw it before I moved it
+            }
+        }
+        find_snapshot(&self.sent, view_ts).map(|entities| entities.players[seen].pos)
+    }
+    // moves a door of `color`'s elsewhere, once they went through it, and tells everyone
+    pub fn relocate_door(&mut self, world: &mut World, color: PlayerColor, index: usize) {
+        let doors = &mut world.doors[color];
+        let old = doors[index];
+        doors[index] = world.room.random_new_my_door(&mut self.rng, doors);
+        if let Some(client) = &mut self.clients[color] {
+            client.retired_door = Some(old);
+        }
+        self.broadcast(Event::DoorMoved { color, index: index as u8, door: doors[index] });
+    }
+    fn kick(&mut self, color: PlayerColor) {
+        if let Some(mut client) = self.clients[color].take() {
+            println!("Kicked {:?} at {:?}", color, client.addr);
+            let reject = Msg::StcReject { reason: RejectReason::Kicked };
+            let addr = client.addr;
+            let _ = self.io.with_sealed_msg(&mut client.session, &reject, |bytes, udp| {
+                udp.send_to(bytes, addr)
+            });
+            self.banned.push((addr.ip(), Instant::now()));
+            self.freed.push(color);
+        }
+    }
+    fn is_banned(&mut self, ip: IpAddr) -> bool {
+        self.banned.retain(|&(_, at)| at.elapsed() < BAN_DURATION);
+        self.banned.iter().any(|&(banned, _)| banned == ip)
+    }
+    // whether a client plays `color`
+    pub fn has_client(&self, color: PlayerColor) -> bool {
+        self.clients[color].is_some()
+    }
+    // the colors that lost their clients since I was last asked. Their AIs may play them again
+    pub fn take_freed_colors(&mut self) -> Vec<PlayerColor> {
+        std::mem::take(&mut self.freed)
+    }
+    fn client_color_of(&self, addr: SocketAddr) -> Option<PlayerColor> {
+        PlayerColor::iter_domain()
+            .find(|&color| self.clients[color].as_ref().map(|c| c.addr == addr).unwrap_or(false))
+    }
+    // the event will reach every client, eventually
+    pub fn broadcast(&mut self, event: Event) {
+        for client in self.clients.iter_mut().flatten().chain(self.spectators.iter_mut()) {
+            client.reliable.push(event.clone());
+        }
+    }
+    // sealed if they are joining, so that they can tell it from a forgery
+    fn reject_joiner(&mut self, reason: RejectReason, addr: SocketAddr) {
+        let Self { io, pending, .. } = self;
+        match pending.iter_mut().find(|p| p.addr == addr) {
+            Some(p) => {
+                let reject = Msg::StcReject { reason };
+                let _ = io.with_sealed_msg(&mut p.session, &reject, |bytes, udp| {
+                    udp.send_to(bytes, addr)
+                });
+            }
+            None => Self::send_reject_to(&mut io.udp, reason, addr),
+        }
+    }
+    fn send_reject_to(udp: &mut Socket, reason: RejectReason, addr: SocketAddr) {
+        let bytes = bincode_config().serialize(&Msg::StcReject { reason }).unwrap();
+        let _ = udp.send_to(&bytes, addr);
+    }
+    fn send_hello_to(&mut self, your_color: Option<PlayerColor>, world: &World, addr: SocketAddr) {
+        let hello = Msg::StcHello {
+            your_color,
+            room_seed: self.room_seed,
+            server_entities: Cow::Borrowed(&world.entities),
+            server_ts: self.server_ts,
+            doors: Cow::Borrowed(&world.doors),
+            rules: Cow::Borrowed(&world.rules),
+        };
+        let Self { clients, spectators, io, .. } = self;
+        let client =
+            clients.iter_mut().flatten().chain(spectators.iter_mut()).find(|c| c.addr == addr);
+        if let Some(client) = client {
+            let _ = io.with_sealed_msg(&mut client.session, &hello, |bytes, udp| {
+                udp.send_to(bytes, addr)
+            });
+        }
+    }
+    // `my_color` is None if no player is controlled by the server itself.
+    // `event_callback` is given the events that clients sent, which are also relayed to all clients.
+    // Err only if my own socket fails. Unreachable clients are dropped instead
+    pub fn update(
+        &mut self,
+        my_color: Option<PlayerColor>,
+        world: &mut World,
+        mut new_client_callback: impl FnMut(PlayerColor, &mut Entities),
+        mut event_callback: impl FnMut(Event),
+    ) -> Result<(), NetError> {
+        // I am the server!
+        let mut relayed = vec![];
+        let mut used_doors = vec![];
+        let mut kicked = vec![];
+        while let Some((msg, sender_addr, sealed)) = self.recv_from()? {
+            match msg {
+                Msg::CtsHello { protocol_version, .. } if protocol_version != PROTOCOL_VERSION => {
+                    let reason =
+                        RejectReason::ProtocolMismatch { server_version: PROTOCOL_VERSION };
+                    Self::send_reject_to(&mut self.io.udp, reason, sender_addr);
+                }
+                Msg::CtsHello { ruleset_hash: hash, .. } if hash != ruleset_hash() => {
+                    Self::send_reject_to(
+                        &mut self.io.udp,
+                        RejectReason::RulesetMismatch,
+                        sender_addr,
+                    );
+                }
+                Msg::CtsHello { .. } if self.is_banned(sender_addr.ip()) => {
+                    Self::send_reject_to(&mut self.io.udp, RejectReason::Kicked, sender_addr);
+                }
+                Msg::CtsHello { client_public, .. } => {
+                    self.send_challenge_to(sender_addr, client_public)
+                }
+                _ if !sealed => {} // everything else must come from an established session
+                Msg::CtsJoin { spectate: true, client_ts, interpolation_delay_ticks, .. } => {
+                    let known = self.spectators.iter().any(|s| s.addr == sender_addr);
+                    if !known && self.spectators.len() >= MAX_SPECTATORS {
+                        self.reject_joiner(RejectReason::ServerFull, sender_addr);
+                    } else {
+                        if let Some(session) = self.take_pending(sender_addr) {
+                            let server_ts = self.server_ts;
+                            let spectator = ServerClient::new(
+                                sender_addr,
+                                client_ts,
+                                server_ts,
+                                session,
+                                interpolation_delay_ticks,
+                            );
+                            self.spectators.push(spectator);
+                        }
+                        self.send_hello_to(None, world, sender_addr);
+                    }
+                }
+                Msg::CtsJoin { preferred_color, client_ts, interpolation_delay_ticks, .. } => {
+                    // what color is the sender's player?
+                    let server_ts = self.server_ts;
+                    let your_color = self
+                        // try 1: the color of a client with the sender's addr
+                        .client_color_of(sender_addr)
+                        // try 2: color of a newly-filled client slot
+                        .or_else(|| {
+                            let [b, c] = preferred_color.predator_prey();
+                            let choices = [preferred_color, b, c];
+                            let color = ArrIter::new(choices).find(|&color| {
+                                Some(color) != my_color && self.clients[color].is_none()
+                            })?;
+                            let session = self.take_pending(sender_addr)?;
+                            self.clients[color] = Some(ServerClient::new(
+                                sender_addr,
+                                client_ts,
+                                server_ts,
+                                session,
+                                interpolation_delay_ticks,
+                            ));
+                            new_client_callback(color, &mut world.entities);
+                            Some(color)
+                        });
+                    if let Some(your_color) = your_color {
+                        // yes you've got a color! Reply with info
+                        self.send_hello_to(Some(your_color), world, sender_addr);
+                    } else {
+                        // sorry, cannot support a new player/color
+                        self.reject_joiner(RejectReason::ServerFull, sender_addr);
+                    }
+                }
+                Msg::CtsUpdate { player, client_ts, acked_server_ts, view_ts } => {
+                    let server_ts = self.server_ts;
+                    let color = match self.client_color_of(sender_addr) {
+                        Some(color) if Some(color) != my_color => color,
+                        _ => continue,
+                    };
+                    let client = self.clients[color].as_mut().unwrap();
+                    client.stats.on_update(client_ts);
+                    client.on_acked(server_ts, acked_server_ts);
+                    if client.client_ts < client_ts {
+                        // update player data with newer info!
+                        let from = world.entities.players[color].pos;
+                        let path_open =
+                            world.path_is_open(color, from, player.pos, client.retired_door);
+                        let curr_player = &mut world.entities.players[color];
+                        let unseen_relocation = match (client.relocated_at, acked_server_ts) {
+                            (Some(relocated_at), Some(acked)) => acked < relocated_at,
+                            (relocated_at, _) => relocated_at.is_some(),
+                        };
+                        if unseen_relocation {
+                            // they have not seen where I moved them. Ignore their position
+                        } else if client.relocated_at.take().is_some() {
+                            // they saw it. They only keep their position if it's a SMALL STEP away
+                            let diff = curr_player.pos - player.pos;
+                            if diff.distances_from_zero() < accepted_client_move(&world.rules) {
+                                curr_player.pos = player.pos;
+                            }
+                            client.accepted_client_ts = client_ts;
+                            client.accepted_server_ts = server_ts;
+                        } else if client.move_is_plausible(
+                            world.rules.move_size(),
+                            curr_player.pos,
+                            player.pos,
+                            client_ts,
+                            server_ts,
+                        ) && path_open
+                        {
+                            curr_player.pos = player.pos;
+                            client.accepted_client_ts = client_ts;
+                            client.accepted_server_ts = server_ts;
+                        } else {
+                            // too far, too fast, or through a wall! Keep their player where it was
+                            client.violations += 1;
+                            println!(
+                                "{:?} at {:?} made an impossible move ({} so far)",
+                                color, sender_addr, client.violations
+                            );
+                            let max = self.kick_after_violations;
+                            if max.map(|max| client.violations >= max).unwrap_or(false) {
+                                kicked.push(color);
+                            }
+                        }
+                        curr_player.vel = player.vel;
+                        client.client_ts = client_ts;
+                        client.acked_server_ts = acked_server_ts;
+                        client.view_ts = client.bounded_view_ts(view_ts, acked_server_ts);
+                    }
+                }
+                Msg::CtsSpectate { client_ts, acked_server_ts } => {
+                    let server_ts = self.server_ts;
+                    if let Some(spectator) =
+                        self.spectators.iter_mut().find(|s| s.addr == sender_addr)
+                    {
+                        spectator.stats.on_update(client_ts);
+                        spectator.on_acked(server_ts, acked_server_ts);
+                        if spectator.client_ts < client_ts {
+                            spectator.client_ts = client_ts;
+                            spectator.acked_server_ts = acked_server_ts;
+                        }
+                    }
+                }
+                Msg::CtsLeaving => self.drop_client(sender_addr),
+                Msg::ReliableAck { next_expected_seq } => {
+                    if let Some(client) = self.client_by_addr_mut(sender_addr) {
+                        client.reliable.on_ack(next_expected_seq);
+                    }
+                }
+                Msg::Reliable { first_seq, events } => {
+                    // owned, so that I can borrow the rest of myself
+                    let events: Cow<[Event]> = Cow::Owned(events.into_owned());
+                    let from = self.client_color_of(sender_addr);
+                    if let Some(client) = self.client_by_addr_mut(sender_addr) {
+                        let events: Vec<Event> =
+                            client.reliable.on_recv(first_seq, events).collect();
+                        for event in events {
+                            if let (Event::DoorUsed { index }, Some(color)) = (&event, from) {
+                                used_doors.push((color, *index as usize));
+                            } else if let Some(event) = client.vetted(event, from) {
+                                relayed.push(event);
+                            }
+                        }
+                    }
+                }
+                // only servers send these. `Sealed` was already opened
+                Msg::StcHello { .. }
+                | Msg::StcUpdate { .. }
+                | Msg::StcReject { .. }
+                | Msg::StcChallenge { .. }
+                | Msg::StcHostState { .. }
+                | Msg::StcLeaving
+                | Msg::Sealed { .. } => {}
+            }
+        }
+        for event in relayed {
+            self.broadcast(event.clone());
+            event_callback(event);
+        }
+        for (color, index) in used_doors {
+            if index < world.doors[color].len() {
+                self.relocate_door(world, color, index);
+            }
+        }
+        for color in kicked {
+            self.kick(color);
+        }
+        // update all clients! each relative to the newest snapshot they have
+        push_snapshot(&mut self.sent, self.server_ts, world.entities.clone());
+        let Self { clients, spectators, io, sent, server_ts, .. } = self;
+        let peer_clients = clients
+            .iter_mut()
+            .zip(PlayerColor::iter_domain())
+            .filter(|(_, color)| Some(*color) != my_color)
+            .filter_map(|(client, _)| client.as_mut());
+        for client in peer_clients.chain(spectators.iter_mut()) {
+            let base_ts = client.acked_server_ts.filter(|&ts| find_snapshot(sent, ts).is_some());
+            let base = base_ts.and_then(|ts| find_snapshot(sent, ts));
+            let update_msg = Msg::StcUpdate {
+                server_ts: *server_ts,
+                echo_client_ts: client.client_ts,
+                base_ts,
+                snapshot: Cow::Owned(delta::encode(base, &world.entities)),
+            };
+            let addr = client.addr;
+            let sent = io
+                .with_sealed_msg(&mut client.session, &update_msg, |bytes, udp| {
+                    udp.send_to(bytes, addr)
+                })
+                .and_then(|sent| {
+                    client.stats.on_sent(sent);
+                    io.send_reliable(&mut client.reliable, &mut client.session, Some(addr))
+                });
+            match sent {
+                Ok(sent) => {
+                    client.stats.on_sent(sent);
+                    client.send_failures = 0;
+                }
+                Err(e) if is_would_block(&e) => {}
+                Err(e) => {
+                    // only their address is to blame. My socket's own failures show as I receive
+                    if client.send_failures == 0 {
+                        println!("Failed to send to {:?}: {}", addr, e);
+                    }
+                    client.send_failures = client.send_failures.saturating_add(1);
+                }
+            }
+            client.stats.tick();
+            client.chat_credit = (client.chat_credit + 1).min(MAX_CHAT_CREDIT);
+        }
+        self.drop_lost();
+        self.share_host_state(world);
+        self.respond_to_discovery(my_color, &world.rules);
+        let oldest_sent = self.sent.front().map(|&(ts, _)| ts);
+        for relocated_at in self.relocated_at.iter_mut() {
+            if *relocated_at < oldest_sent {
+                *relocated_at = None; // no snapshot from before then remains
+            }
+        }
+        self.server_ts += 1u16;
+        Ok(())
+    }
+    // best effort. For messages that are sent again soon, or matter only once
+    fn send_to_all(&mut self, msg: &Msg) {
+        let Self { clients, spectators, io, .. } = self;
+        for client in clients.iter_mut().flatten().chain(spectators.iter_mut()) {
+            Self::send_to_client(io, client, msg);
+        }
+    }
+    fn send_to_client(io: &mut Io, client: &mut ServerClient, msg: &Msg) {
+        let addr = client.addr;
+        let sent =
+            io.with_sealed_msg(&mut client.session, msg, |bytes, udp| udp.send_to(bytes, addr));
+        if let Ok(sent) = sent {
+            client.stats.on_sent(sent);
+        }
+    }
+    // every `HOST_STATE_TICKS`, in case I go away. Spectators are not told who would host next,
+    // so they do not learn the players' addresses
+    fn share_host_state(&mut self, world: &World) {
+        if let Some(ticks) = self.ticks_until_host_state.checked_sub(1) {
+            self.ticks_until_host_state = ticks;
+            return;
+        }
+        self.ticks_until_host_state = HOST_STATE_TICKS;
+        // a successor continues from exactly this state of my rng
+        let rng_seed = self.rng.fastrand_rng.u64(..);
+        self.rng = Rng::new_seeded(rng_seed);
+        let clients = &self.clients;
+        let state = HostState {
+            server_ts: self.server_ts,
+            entities: world.entities.clone(),
+            doors: world.doors.clone(),
+            room_seed: self.room_seed,
+            rng_seed,
+            ai_colors: PlayerColor::iter_domain().filter(|&c| clients[c].is_none()).collect(),
+            ai_kind: self.ai_kind,
+            successors: PlayerColor::iter_domain()
+                .filter_map(|color| Some((color, clients[color].as_ref()?.addr)))
+                .collect(),
+        };
+        let msg = Msg::StcHostState { state };
+        let Self { clients, io, .. } = self;
+        for client in clients.iter_mut().flatten() {
+            Self::send_to_client(io, client, &msg);
+        }
+    }
+    // tells the clients not to wait for me to go silent before they migrate
+    pub fn leave(&mut self) {
+        self.send_to_all(&Msg::StcLeaving);
+    }
+    // forgets clients that have been unreachable or silent for long. Their colors become free
+    fn drop_lost(&mut self) {
+        let server_ts = self.server_ts;
+        for color in PlayerColor::iter_domain() {
+            let client = &self.clients[color];
+            if client.as_ref().map(|c| c.is_lost(server_ts)).unwrap_or(false) {
+                let client = self.clients[color].take().unwrap();
+                println!("Dropped {:?} at {:?}. It is gone", color, client.addr);
+                self.freed.push(color);
+            }
+        }
+        self.spectators.retain(|spectator| {
+            let lost = spectator.is_lost(server_ts);
+            if lost {
+                println!("Dropped spectator at {:?}. It is gone", spectator.addr);
+            }
+            !lost
+        });
+    }
+    // they said they are leaving. Their color is free at once
+    fn drop_client(&mut self, addr: SocketAddr) {
+        if let Some(color) = self.client_color_of(addr) {
+            self.clients[color] = None;
+            println!("{:?} at {:?} left", color, addr);
+            self.freed.push(color);
+        }
+        self.spectators.retain(|spectator| spectator.addr != addr);
+    }
+}
@@ -0,0 +1,104 @@
+use {
+    crate::{
+        game::{Door, PlayerColor},
+        prelude::*,
+    },
+    std::{borrow::Cow, collections::VecDeque},
+};
+
+/*
+One direction of a reliable, ordered stream of events, and the receiving end of the other.
+Go-back-N: the sender sends ALL unacked events (up to a cap) together, starting from the oldest.
+The receiver delivers the events it has not yet seen, and acks with the next sequence number it expects.
+Unacked events are resent every RESEND_TICKS until acked.
+*/
+pub type Seq = WrapInt;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Event {
+    Captured { predator: PlayerColor, prey: PlayerColor },
+    // `from` is None for spectators. The server overwrites whatever a client claims
+    Chat { from: Option<PlayerColor>, text: String },
+    Ping { from: Option<PlayerColor>, kind: PingKind, pos: Pos },
+    // from a client: I went through my door with this index. The server relocates it
+    DoorUsed { index: u8 },
+    // from the server: this player's door with this index moved
+    DoorMoved { color: PlayerColor, index: u8, door: Door },
+    // from the server, to its sender only: a chat or ping came too fast, and was not relayed
+    ChatDropped,
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub enum PingKind {
+    LookHere,
+    Danger,
+    NeedHelp,
+}
+
+#[derive(Default)]
+pub struct ReliableChannel {
+    // sending
+    unacked: VecDeque<Event>,
+    unacked_first_seq: Seq,
+    ticks_until_resend: u8,
+    // receiving
+    next_expected_seq: Seq,
+    ack_due: bool,
+}
+
+/////////////////////////////////////////////////
+impl ReliableChannel {
+    const RESEND_TICKS: u8 = 10;
+    const MAX_BATCH: usize = 8;
+    pub fn push(&mut self, event: Event) {
+        self.unacked.push_back(event);
+        self.ticks_until_resend = 0;
+    }
+    // call once per tick. Returns the batch of events to send now, if any
+    pub fn to_send(&mut self) -> Option<(Seq, Cow<[Event]>)> {
+        if self.unacked.is_empty() {
+            return None;
+        }
+        if let Some(ticks) = self.ticks_until_resend.checked_sub(1) {
+            self.ticks_until_resend = ticks;
+            return None;
+        }
+        self.ticks_until_resend = Self::RESEND_TICKS;
+        let unacked = self.unacked.make_contiguous();
+        let batch = &unacked[..unacked.len().min(Self::MAX_BATCH)];
+        Some((self.unacked_first_seq, Cow::Borrowed(batch)))
+    }
+    pub fn on_ack(&mut self, next_expected_seq: Seq) {
+        let acked: i16 = (next_expected_seq - self.unacked_first_seq).into();
+        if 0 < acked && acked as usize <= self.unacked.len() {
+            self.unacked.drain(..acked as usize);
+            self.unacked_first_seq = next_expected_seq;
+        }
+    }
+    // returns the events that are new, in order
+    pub fn on_recv<'a>(
+        &'a mut self,
+        first_seq: Seq,
+        events: Cow<'a, [Event]>,
+    ) -> impl Iterator<Item = Event> + 'a {
+        self.ack_due = true;
+        events.into_owned().into_iter().enumerate().filter_map(move |(i, event)| {
+            if first_seq + i as u16 == self.next_expected_seq {
+                self.next_expected_seq += 1u16;
+                Some(event)
+            } else {
+                // already seen it
+                None
+            }
+        })
+    }
+    // Some(seq) IFF the sender should be told that I expect `seq` next
+    pub fn take_ack(&mut self) -> Option<Seq> {
+        if self.ack_due {
+            self.ack_due = false;
+            Some(self.next_expected_seq)
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use {
+    chacha20poly1305::{
+        aead::{Aead, NewAead},
+        ChaCha20Poly1305, Key, Nonce,
+    },
+    rand_core::OsRng,
+    sha2::{Digest, Sha256},
+    x25519_dalek::{EphemeralSecret, PublicKey},
+};
+
+/*
+An encrypted, authenticated session between a client and the server.
+1. Each side generates an ephemeral X25519 key pair. The client's public key is sent in `CtsHello`,
+   the server's in `StcChallenge`.
+2. Both derive the same ChaCha20-Poly1305 key by hashing the shared secret with both public keys
+   and the server's password (if any). Only holders of the password derive the right key.
+3. Every later `Msg` is sealed into a `Msg::Sealed`. Its nonce is the sender's direction and a counter,
+   so nonces are never reused, and replays (and very late arrivals) are dropped.
+*/
+pub type PublicBytes = [u8; 32];
+
+pub struct Session {
+    cipher: ChaCha20Poly1305,
+    i_am_server: bool,
+    next_send_counter: u64,
+    recv_window: ReplayWindow,
+}
+
+#[derive(Default)]
+struct ReplayWindow {
+    newest: Option<u64>,
+    seen_bits: u64, // bit i set IFF `newest - i` was seen
+}
+
+//////////////////////////////////
+pub fn new_key_pair() -> (EphemeralSecret, PublicBytes) {
+    let secret = EphemeralSecret::new(OsRng);
+    let public = PublicKey::from(&secret).to_bytes();
+    (secret, public)
+}
+impl ReplayWindow {
+    // true IFF `counter` was not seen before, and is not too old to tell
+    fn check_and_insert(&mut self, counter: u64) -> bool {
+        let newest = match self.newest {
+            None => {
+                self.newest = Some(counter);
+                self.seen_bits = 1;
+                return true;
+            }
+            Some(newest) => newest,
+        };
+        if counter > newest {
+            let shift = counter - newest;
+            self.seen_bits = (if shift < 64 { self.seen_bits << shift } else { 0 }) | 1;
+            self.newest = Some(counter);
+            true
+        } else {
+            let age = newest - counter;
+            let bit = 1u64 << age.min(63);
+            if age < 64 && self.seen_bits & bit == 0 {
+                self.seen_bits |= bit;
+                true
+            } else {
+                false
+            }
+        }
+    }
+}
+impl Session {
+    pub fn new(
+        my_secret: EphemeralSecret,
+        their_public: PublicBytes,
+        password: Option<&str>,
+        i_am_server: bool,
+    ) -> Self {
+        let my_public = PublicKey::from(&my_secret).to_bytes();
+        let shared = my_secret.diffie_hellman(&PublicKey::from(their_public));
+        let [client_public, server_public] =
+            if i_am_server { [their_public, my_public] } else { [my_public, their_public] };
+        let mut hasher = Sha256::new();
+        hasher.update(b"honeydew session key");
+        hasher.update(shared.as_bytes());
+        hasher.update(client_public);
+        hasher.update(server_public);
+        hasher.update(password.unwrap_or("").as_bytes());
+        let key = hasher.finalize();
+        Self {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+            i_am_server,
+            next_send_counter: 0,
+            recv_window: Default::default(),
+        }
+    }
+    fn nonce_bytes(from_server: bool, counter: u64) -> [u8; 12] {
+        let mut nonce = [0; 12];
+        nonce[0] = from_server as u8;
+        nonce[4..].copy_from_slice(&counter.to_le_bytes());
+        nonce
+    }
+    // returns the counter and ciphertext to put in a `Msg::Sealed`
+    pub fn seal(&mut self, plaintext: &[u8]) -> (u64, Vec<u8>) {
+        let counter = self.next_send_counter;
+        self.next_send_counter += 1;
+        let nonce = Self::nonce_bytes(self.i_am_server, counter);
+        let ciphertext =
+            self.cipher.encrypt(Nonce::from_slice(&nonce), plaintext).expect("Encryption failed");
+        (counter, ciphertext)
+    }
+    // None if the ciphertext is forged, corrupted, replayed, or was sealed with another key
+    pub fn open(&mut self, counter: u64, ciphertext: &[u8]) -> Option<Vec<u8>> {
+        let nonce = Self::nonce_bytes(!self.i_am_server, counter);
+        let plaintext = self.cipher.decrypt(Nonce::from_slice(&nonce), ciphertext).ok()?;
+        // only authentic messages may move the window
+        if self.recv_window.check_and_insert(counter) {
+            Some(plaintext)
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,168 @@
+use {
+    crate::{game::config::NetSimConfig, prelude::*},
+    std::{
+        io,
+        net::{SocketAddr, UdpSocket},
+        time::Instant,
+    },
+};
+
+// A UDP socket that (optionally) simulates a bad network, in both directions.
+pub struct Socket {
+    udp: UdpSocket, // nonblocking IFF sim is Some
+    sim: Option<NetSim>,
+    // how the socket behaves to the user
+    nonblocking: bool,
+    read_timeout: Option<Duration>,
+}
+struct NetSim {
+    config: NetSimConfig,
+    rng: fastrand::Rng,
+    outgoing: Vec<Delayed>,
+    incoming: Vec<Delayed>,
+}
+struct Delayed {
+    due: Instant,
+    bytes: Vec<u8>,
+    addr: Option<SocketAddr>, // None for the connected peer
+}
+
+/////////////////////////////////////
+impl NetSimConfig {
+    pub const ENV_VAR: &'static str = "HONEYDEW_NET_SIM";
+    // e.g. HONEYDEW_NET_SIM="(latency_ms:100,jitter_ms:20,loss:0.05,duplication:0.01,reorder:0.02)"
+    pub fn from_env() -> Option<Self> {
+        let s = std::env::var(Self::ENV_VAR).ok()?;
+        match ron::de::from_str(&s) {
+            Ok(config) => Some(config),
+            Err(e) => {
+                println!("Ignoring malformed {}: {}", Self::ENV_VAR, e);
+                None
+            }
+        }
+    }
+}
+impl NetSim {
+    // schedules a datagram into `queue` with simulated latency, jitter, loss, duplication and reordering
+    fn schedule(&mut self, bytes: &[u8], addr: Option<SocketAddr>, outgoing: bool) {
+        let NetSimConfig { latency_ms, jitter_ms, loss, duplication, reorder } = self.config;
+        if self.rng.f32() < loss {
+            return;
+        }
+        let copies = if self.rng.f32() < duplication { 2 } else { 1 };
+        for _ in 0..copies {
+            let mut delay_ms = latency_ms + self.rng.u32(0..=jitter_ms);
+            if self.rng.f32() < reorder {
+                // held back long enough for later datagrams to overtake it
+                delay_ms += latency_ms.max(1) + jitter_ms;
+            }
+            let due = Instant::now() + Duration::from_millis(delay_ms as u64);
+            let delayed = Delayed { due, bytes: bytes.to_vec(), addr };
+            if outgoing { &mut self.outgoing } else { &mut self.incoming }.push(delayed);
+        }
+    }
+    fn take_due(queue: &mut Vec<Delayed>) -> Option<Delayed> {
+        let now = Instant::now();
+        let (i, _) =
+            queue.iter().enumerate().filter(|(_, d)| d.due <= now).min_by_key(|(_, d)| d.due)?;
+        Some(queue.remove(i))
+    }
+}
+impl Socket {
+    pub fn bind(addr: SocketAddr, sim: Option<&NetSimConfig>) -> io::Result<Self> {
+        let sim = sim.map(|config| NetSim {
+            config: config.clone(),
+            rng: fastrand::Rng::new(),
+            outgoing: vec![],
+            incoming: vec![],
+        });
+        let udp = super::bind_udp(addr)?;
+        if sim.is_some() {
+            // blocking is simulated instead
+            udp.set_nonblocking(true)?;
+        }
+        Ok(Self { udp, sim, nonblocking: false, read_timeout: None })
+    }
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.udp.local_addr()
+    }
+    pub fn set_nonblocking(&mut self, nonblocking: bool) -> io::Result<()> {
+        self.nonblocking = nonblocking;
+        if self.sim.is_none() {
+            self.udp.set_nonblocking(nonblocking)?;
+        }
+        Ok(())
+    }
+    pub fn set_read_timeout(&mut self, dur: Option<Duration>) -> io::Result<()> {
+        self.read_timeout = dur;
+        self.udp.set_read_timeout(dur)
+    }
+    pub fn connect(&self, addr: SocketAddr) -> io::Result<()> {
+        self.udp.connect(addr)
+    }
+    // actually sends any delayed outgoing datagrams that are due.
+    // Their failures are lost like datagrams, rather than blamed on whatever is sent next
+    fn flush(&mut self) {
+        if let Some(sim) = &mut self.sim {
+            while let Some(Delayed { bytes, addr, .. }) = NetSim::take_due(&mut sim.outgoing) {
+                let _ = match addr {
+                    Some(addr) => self.udp.send_to(&bytes, addr),
+                    None => self.udp.send(&bytes),
+                };
+            }
+        }
+    }
+    fn send_maybe_to(&mut self, bytes: &[u8], addr: Option<SocketAddr>) -> io::Result<usize> {
+        match &mut self.sim {
+            Some(sim) => {
+                sim.schedule(bytes, addr, true);
+                self.flush();
+                Ok(bytes.len())
+            }
+            None => match addr {
+                Some(addr) => self.udp.send_to(bytes, addr),
+                None => self.udp.send(bytes),
+            },
+        }
+    }
+    pub fn send(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.send_maybe_to(bytes, None)
+    }
+    pub fn send_to(&mut self, bytes: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        self.send_maybe_to(bytes, Some(addr))
+    }
+    pub fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        if self.sim.is_none() {
+            return self.udp.recv_from(buf);
+        }
+        let started = Instant::now();
+        loop {
+            self.flush();
+            let Self { udp, sim, .. } = self;
+            let sim = sim.as_mut().unwrap();
+            // move everything that has arrived into the simulated incoming queue
+            loop {
+                match udp.recv_from(buf) {
+                    Ok((n, addr)) => sim.schedule(&buf[..n], Some(addr), false),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(e),
+                }
+            }
+            if let Some(Delayed { bytes, addr, .. }) = NetSim::take_due(&mut sim.incoming) {
+                let n = bytes.len().min(buf.len());
+                buf[..n].copy_from_slice(&bytes[..n]);
+                return Ok((n, addr.unwrap()));
+            }
+            if self.nonblocking {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            if self.read_timeout.map(|timeout| started.elapsed() >= timeout).unwrap_or(false) {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+    pub fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.recv_from(buf).map(|(n, _)| n)
+    }
+}
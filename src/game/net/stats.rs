@@ -0,0 +1,118 @@
+use crate::prelude::*;
+
+/*
+Network statistics about one peer, as seen from this end.
+1. Bytes and the peer's per-tick updates are counted over a window of `TICKS_PER_SEC` ticks.
+   At the end of each window, they are summarized into a `NetStats`.
+2. Loss is inferred from gaps in the timestamps of the peer's updates, which it sends once per tick.
+3. Round trip times come from timestamps the peer echoes back, and are smoothed.
+*/
+#[derive(Debug, Copy, Clone, Default)]
+pub struct NetStats {
+    // None until the peer has echoed something
+    pub rtt_ticks: Option<f32>,
+    // fraction of the peer's updates that never arrived in the last window. In 0..=1
+    pub loss: f32,
+    pub sent_bytes_per_sec: u32,
+    pub recv_bytes_per_sec: u32,
+    // age of the newest snapshot the client has
+    pub snapshot_age_ticks: u16,
+}
+
+#[derive(Default)]
+pub struct StatsMeter {
+    summary: NetStats,
+    window_ticks: u16,
+    sent_bytes: u32,
+    recv_bytes: u32,
+    // updates newer than `window_start_ts` that arrived this window
+    updates_arrived: u16,
+    window_start_ts: Option<WrapInt>,
+    newest_update_ts: Option<WrapInt>,
+}
+
+// every game ticks at about this rate
+pub const TICKS_PER_SEC: u16 = 60;
+const RTT_SMOOTHING: f32 = 0.125;
+
+///////////////////////////////////////////
+impl NetStats {
+    pub fn rtt_millis(&self) -> Option<u32> {
+        self.rtt_ticks.map(|ticks| (ticks * 1000. / TICKS_PER_SEC as f32) as u32)
+    }
+}
+impl std::fmt::Display for NetStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.rtt_millis() {
+            Some(millis) => write!(f, "rtt {}ms", millis)?,
+            None => write!(f, "rtt ?")?,
+        }
+        write!(
+            f,
+            ", loss {:.0}%, up {}B/s, down {}B/s, snapshot age {} ticks",
+            self.loss * 100.,
+            self.sent_bytes_per_sec,
+            self.recv_bytes_per_sec,
+            self.snapshot_age_ticks,
+        )
+    }
+}
+impl StatsMeter {
+    // as of the end of the last window, except the RTT and snapshot age, which are current
+    pub fn summary(&self) -> NetStats {
+        self.summary
+    }
+    pub fn on_sent(&mut self, bytes: usize) {
+        self.sent_bytes = self.sent_bytes.saturating_add(bytes as u32);
+    }
+    pub fn on_recv(&mut self, bytes: usize) {
+        self.recv_bytes = self.recv_bytes.saturating_add(bytes as u32);
+    }
+    // `peer_ts` is the peer's clock, which stamps one update per tick
+    pub fn on_update(&mut self, peer_ts: WrapInt) {
+        match self.window_start_ts {
+            None => self.window_start_ts = Some(peer_ts),
+            Some(start) if peer_ts > start => self.updates_arrived += 1,
+            Some(_) => {} // late. Counted as lost already
+        }
+        if self.newest_update_ts.map(|newest| peer_ts > newest).unwrap_or(true) {
+            self.newest_update_ts = Some(peer_ts);
+        }
+    }
+    // `ticks` is how long ago the peer's echoed timestamp was sent
+    pub fn on_rtt_sample(&mut self, ticks: i16) {
+        if ticks < 0 {
+            return; // echoed something I never sent
+        }
+        let sample = ticks as f32;
+        self.summary.rtt_ticks = Some(match self.summary.rtt_ticks {
+            Some(rtt) => rtt + (sample - rtt) * RTT_SMOOTHING,
+            None => sample,
+        });
+    }
+    pub fn set_snapshot_age(&mut self, ticks: u16) {
+        self.summary.snapshot_age_ticks = ticks;
+    }
+    // call once per tick
+    pub fn tick(&mut self) {
+        self.window_ticks += 1;
+        if self.window_ticks < TICKS_PER_SEC {
+            return;
+        }
+        // the window is over, a second long. Summarize it
+        self.summary.sent_bytes_per_sec = self.sent_bytes;
+        self.summary.recv_bytes_per_sec = self.recv_bytes;
+        if let (Some(start), Some(newest)) = (self.window_start_ts, self.newest_update_ts) {
+            let expected: i16 = (newest - start).into();
+            if expected > 0 {
+                let arrived = self.updates_arrived.min(expected as u16);
+                self.summary.loss = 1. - arrived as f32 / expected as f32;
+            }
+        }
+        self.window_ticks = 0;
+        self.sent_bytes = 0;
+        self.recv_bytes = 0;
+        self.updates_arrived = 0;
+        self.window_start_ts = self.newest_update_ts.or(self.window_start_ts);
+    }
+}
@@ -0,0 +1,503 @@
+// A server and a client over loopback, each through a simulated bad network
+use {
+    super::*,
+    crate::game::{
+        config::{AiKind, IfClient, IfServer, NetSimConfig},
+        room::HALF_ROOM_SIZE,
+    },
+    std::{collections::VecDeque, sync::mpsc, thread},
+};
+
+// faster than real ticks, so that the network is relatively slower than it would be
+const TICK: Duration = Duration::from_millis(5);
+const JOIN_PATIENCE: Duration = Duration::from_secs(10);
+// how far the client's view of the server's player may lag behind it, while moving
+const MAX_LAG_STEPS: u16 = 60;
+
+struct Match {
+    server: Server,
+    server_world: World,
+    server_color: PlayerColor,
+    client: Client,
+    client_world: World,
+    client_color: PlayerColor,
+    // where each player walks, until a wall turns it somewhere random
+    headings: PlayerArr<Direction>,
+    rng: fastrand::Rng,
+}
+
+fn lossy_jittery_sim() -> NetSimConfig {
+    NetSimConfig { latency_ms: 20, jitter_ms: 15, loss: 0.1, duplication: 0.02, reorder: 0.05 }
+}
+// a round trip well within `MAX_REWIND_TICKS`, even at my fast ticks
+fn quick_lossy_sim() -> NetSimConfig {
+    NetSimConfig { latency_ms: 4, jitter_ms: 4, loss: 0.1, duplication: 0.02, reorder: 0.05 }
+}
+fn steady_sim() -> NetSimConfig {
+    NetSimConfig { latency_ms: 20, jitter_ms: 0, loss: 0., duplication: 0., reorder: 0. }
+}
+fn server_config() -> IfServer {
+    IfServer {
+        server_addr: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0),
+        player_color: PlayerColor::Black,
+        room_seed: Some(7),
+        ai_enabled: vec![],
+        ai_kind: AiKind::default(),
+        bots: vec![],
+        password: None,
+        name: IfServer::default_name(),
+        discovery_addr: None,
+        kick_after_violations: None,
+    }
+}
+fn client_config() -> IfClient {
+    IfClient {
+        server_addr: String::new(), // given to `Client::new` instead
+        preferred_color: PlayerColor::Blue,
+        interpolation_delay_ticks: 6,
+        via_lobby: None,
+        discover: None,
+        spectate: false,
+        password: None,
+    }
+}
+// the longest axis of the difference between `a` and `b`, in steps of a player
+fn steps_apart(rules: &Rules, a: Pos, b: Pos) -> u16 {
+    let distances = (a - b).distances_from_zero();
+    let move_size = rules.move_size();
+    Dim::iter_domain().map(|dim| distances[dim] / move_size[dim]).max().unwrap()
+}
+// a client joins on another thread, while the server ticks.
+// `meddle` is given the server before each of its ticks, until the join ends
+fn join_while_serving(
+    server: &mut Server,
+    server_world: &mut World,
+    server_color: PlayerColor,
+    config: IfClient,
+    sim: Option<&NetSimConfig>,
+    mut meddle: impl FnMut(&mut Server),
+) -> Result<(Client, World, Option<PlayerColor>), JoinError> {
+    let server_addr = server.local_addr();
+    let (sender, receiver) = mpsc::channel();
+    let sim = sim.cloned();
+    thread::spawn(move || {
+        let _ = sender.send(Client::new(&config, server_addr, sim.as_ref()));
+    });
+    let started = Instant::now();
+    loop {
+        assert!(started.elapsed() < JOIN_PATIENCE, "the join did not end in time");
+        meddle(server);
+        server.update(Some(server_color), server_world, |_, _| {}, |_| {}).unwrap();
+        match receiver.try_recv() {
+            Ok(joined) => return joined,
+            Err(mpsc::TryRecvError::Empty) => thread::sleep(TICK),
+            Err(e) => panic!("the client thread died: {}", e),
+        }
+    }
+}
+impl Match {
+    fn join(sim: &NetSimConfig) -> Self {
+        Self::join_meddled(Some(sim), |_| {})
+    }
+    fn join_meddled(sim: Option<&NetSimConfig>, meddle: impl FnMut(&mut Server)) -> Self {
+        let config = server_config();
+        let (mut server, mut server_world, server_color) =
+            Server::new(&config, &Rules::default(), sim).unwrap();
+        let (client, client_world, client_color) = join_while_serving(
+            &mut server,
+            &mut server_world,
+            server_color,
+            client_config(),
+            sim,
+            meddle,
+        )
+        .unwrap();
+        Self {
+            server,
+            server_world,
+            server_color,
+            client,
+            client_world,
+            client_color: client_color.unwrap(),
+            headings: [Right; 3],
+            rng: fastrand::Rng::with_seed(3),
+        }
+    }
+    // moves `color` a step in `world` if that crosses no wall, or else turns it somewhere random
+    fn walk(world: &mut World, color: PlayerColor, heading: &mut Direction, rng: &fastrand::Rng) {
+        let player = &mut world.entities.players[color];
+        let mut vel = Vel::default();
+        vel[heading.dim()] = Some(heading.sign());
+        let from = player.pos;
+        let to = from.moved(&world.rules, vel, 1);
+        if world.path_is_open(color, from, to, None) {
+            let player = &mut world.entities.players[color];
+            player.pos = to;
+            player.vel = vel;
+        } else {
+            world.entities.players[color].vel = Vel::default();
+            *heading = [Up, Down, Left, Right][rng.usize(..4)];
+        }
+    }
+    // one tick of both. Both players walk IFF `walking`. Returns the events the client got
+    fn tick(&mut self, walking: bool) -> Vec<Event> {
+        let Self { server_world, client_world, headings, rng, .. } = self;
+        let [server_color, client_color] = [self.server_color, self.client_color];
+        if walking {
+            Self::walk(server_world, server_color, &mut headings[server_color], rng);
+            Self::walk(client_world, client_color, &mut headings[client_color], rng);
+        } else {
+            for world in ArrIter::new([server_world, client_world]) {
+                for player in world.entities.players.iter_mut() {
+                    player.vel = Vel::default();
+                }
+            }
+        }
+        self.server
+            .update(Some(self.server_color), &mut self.server_world, |_, _| {}, |_| {})
+            .unwrap();
+        let rules = &self.client_world.rules;
+        let mut events = vec![];
+        self.client
+            .update(Some(self.client_color), rules, &mut self.client_world.entities, |event| {
+                events.push(event)
+            })
+            .unwrap();
+        thread::sleep(TICK);
+        events
+    }
+    // how many steps apart the server and client have `color`
+    fn disagreement(&self, color: PlayerColor) -> u16 {
+        let a = self.server_world.entities.players[color].pos;
+        let b = self.client_world.entities.players[color].pos;
+        steps_apart(&self.server_world.rules, a, b)
+    }
+}
+
+#[test]
+fn client_joins_through_a_lossy_network() {
+    let m = Match::join(&lossy_jittery_sim());
+    assert_ne!(m.client_color, m.server_color);
+    assert_eq!(m.client_world.entities.teleporters, m.server_world.entities.teleporters);
+}
+
+#[test]
+fn views_converge_through_a_lossy_network() {
+    let mut m = Match::join(&lossy_jittery_sim());
+    let started_at = m.server_world.entities.players.clone();
+    for _ in 0..200 {
+        m.tick(true);
+        // the client sees the server's player where it was a while ago
+        let lag = m.disagreement(m.server_color);
+        assert!(lag <= MAX_LAG_STEPS, "the client lags {} steps behind", lag);
+    }
+    for _ in 0..100 {
+        m.tick(false);
+    }
+    for &color in [m.server_color, m.client_color].iter() {
+        assert_ne!(m.server_world.entities.players[color].pos, started_at[color].pos);
+    }
+    // once everyone stands still, everyone agrees where everyone is
+    for color in PlayerColor::iter_domain() {
+        assert_eq!(m.disagreement(color), 0, "{:?} is not where the server has it", color);
+    }
+}
+
+#[test]
+fn relocated_players_snap_instead_of_sliding() {
+    let mut m = Match::join(&lossy_jittery_sim());
+    for _ in 0..50 {
+        m.tick(false);
+    }
+    let color = m.server_color;
+    let from = m.server_world.entities.players[color].pos;
+    let to = from + HALF_ROOM_SIZE.map(WrapInt::from);
+    m.server_world.entities.players[color].pos = to;
+    m.server.on_relocated(color);
+    for _ in 0..100 {
+        m.tick(false);
+        let seen = m.client_world.entities.players[color].pos;
+        let rules = &m.client_world.rules;
+        assert!(
+            steps_apart(rules, seen, from) == 0 || steps_apart(rules, seen, to) == 0,
+            "the client saw {:?} between {:?} and {:?}",
+            seen,
+            from,
+            to
+        );
+    }
+    assert_eq!(m.disagreement(color), 0);
+}
+
+#[test]
+fn chatting_too_fast_is_answered() {
+    let mut m = Match::join(&lossy_jittery_sim());
+    // few enough to arrive together, however many are resent
+    for i in 0..8 {
+        m.client.send_event(Event::Chat { from: None, text: i.to_string() });
+    }
+    let mut chats = 0;
+    let mut dropped = 0;
+    for _ in 0..300 {
+        for event in m.tick(false) {
+            match event {
+                Event::Chat { from, .. } => {
+                    assert_eq!(from, Some(m.client_color));
+                    chats += 1;
+                }
+                Event::ChatDropped => dropped += 1,
+                _ => {}
+            }
+        }
+    }
+    assert_eq!(chats, (MAX_CHAT_CREDIT / CHAT_COST) as usize);
+    assert_eq!(dropped, 1, "told once, however many were dropped");
+}
+
+#[test]
+fn join_survives_the_server_forgetting_its_challenge() {
+    let mut forgot = false;
+    Match::join_meddled(Some(&lossy_jittery_sim()), |server| {
+        if !forgot && !server.pending.is_empty() {
+            // as if evicted by other joiners, or the server restarted
+            server.pending.clear();
+            forgot = true;
+        }
+    });
+    assert!(forgot);
+}
+
+#[test]
+fn forged_rejects_do_not_stop_a_join() {
+    // steady, so that the forgery arrives between the challenge and the server's hello
+    let sim = steady_sim();
+    let mut challenged_at = None;
+    let mut forged = false;
+    Match::join_meddled(Some(&sim), |server| {
+        let p = match server.pending.front() {
+            Some(p) => p,
+            None => return,
+        };
+        let challenged_at = *challenged_at.get_or_insert_with(Instant::now);
+        // from the server's own address, as the client is connected to it. Sent 20ms after the
+        // challenge, it arrives 20ms after it, and long before the hello
+        if !forged && challenged_at.elapsed() >= Duration::from_millis(20) {
+            Server::send_reject_to(&mut server.io.udp, RejectReason::ServerFull, p.addr);
+            forged = true;
+        }
+    });
+    assert!(forged);
+}
+
+#[test]
+fn kicked_clients_cannot_rejoin() {
+    // lossless, as they are told only once
+    let sim = steady_sim();
+    let mut m = Match::join(&sim);
+    m.server.kick(m.client_color);
+    assert!(!m.server.has_client(m.client_color));
+    assert_eq!(m.server.take_freed_colors(), vec![m.client_color]);
+    let rejected = (0..300).any(|_| {
+        m.server.update(Some(m.server_color), &mut m.server_world, |_, _| {}, |_| {}).unwrap();
+        let Match { client, client_world, client_color, .. } = &mut m;
+        let result = client.update(
+            Some(*client_color),
+            &client_world.rules,
+            &mut client_world.entities,
+            |_| {},
+        );
+        thread::sleep(TICK);
+        matches!(result, Err(NetError::Rejected(RejectReason::Kicked)))
+    });
+    assert!(rejected, "the client was not told");
+    let rejoined = join_while_serving(
+        &mut m.server,
+        &mut m.server_world,
+        m.server_color,
+        client_config(),
+        Some(&sim),
+        |_| {},
+    );
+    assert!(matches!(rejoined, Err(JoinError::Rejected(RejectReason::Kicked))));
+}
+
+#[test]
+fn captures_are_judged_as_the_predator_saw_them() {
+    let mut m = Match::join(&quick_lossy_sim());
+    // where the client showed the server's player, newest last
+    let mut shown = VecDeque::new();
+    let mut judged = 0;
+    for _ in 0..300 {
+        m.tick(true);
+        shown.push_back(m.client_world.entities.players[m.server_color].pos);
+        if shown.len() > MAX_REWIND_TICKS as usize {
+            shown.pop_front();
+        }
+        if let Some(seen) = m.server.seen_pos(m.client_color, m.server_color) {
+            judged += 1;
+            let rules = &m.server_world.rules;
+            let nearest = shown.iter().map(|&pos| steps_apart(rules, seen, pos)).min().unwrap();
+            assert!(nearest <= 1, "judged by {:?}, {} steps from anything shown", seen, nearest);
+        }
+    }
+    assert!(judged > 250, "only {} ticks were judged as seen", judged);
+}
+
+#[test]
+fn views_older_than_the_declared_delay_are_not_trusted() {
+    let mut m = Match::join(&quick_lossy_sim());
+    // they show the others later than they said they would, to capture where prey used to be
+    m.client.interpolation_delay_ticks += 12;
+    let max_behind = client_config().interpolation_delay_ticks as i16 + VIEW_TS_SLACK;
+    let mut bounded = 0;
+    for _ in 0..200 {
+        m.tick(true);
+        let client = m.server.clients[m.client_color].as_ref().unwrap();
+        if let (Some(view_ts), Some(acked)) = (client.view_ts, client.acked_server_ts) {
+            let behind: i16 = (acked - view_ts).into();
+            assert!(behind <= max_behind, "trusted a view {} ticks behind", behind);
+            if behind == max_behind {
+                bounded += 1;
+            }
+        }
+    }
+    assert!(bounded > 100, "only {} views were bounded", bounded);
+}
+
+#[test]
+fn failing_to_send_to_a_client_drops_only_it() {
+    let mut m = Match::join_meddled(None, |_| {});
+    // sending there fails without `SO_BROADCAST`, and not because nothing listens there
+    let port = m.server.clients[m.client_color].as_ref().unwrap().addr.port();
+    m.server.clients[m.client_color].as_mut().unwrap().addr =
+        SocketAddr::new(Ipv4Addr::BROADCAST.into(), port);
+    let server_ts = m.server.server_ts;
+    for _ in 0..MAX_SEND_FAILURES {
+        m.server.update(Some(m.server_color), &mut m.server_world, |_, _| {}, |_| {}).unwrap();
+    }
+    assert_eq!(m.server.server_ts, server_ts + MAX_SEND_FAILURES);
+    assert!(!m.server.has_client(m.client_color));
+}
+
+#[test]
+fn silent_clients_are_dropped() {
+    let mut m = Match::join_meddled(None, |_| {});
+    m.tick(true);
+    // the client crashed, or quit without a word. Nothing tells the server
+    for _ in 0..CLIENT_SILENCE_TICKS {
+        m.server.update(Some(m.server_color), &mut m.server_world, |_, _| {}, |_| {}).unwrap();
+        assert!(m.server.has_client(m.client_color), "dropped before it was silent for long");
+    }
+    m.server.update(Some(m.server_color), &mut m.server_world, |_, _| {}, |_| {}).unwrap();
+    assert!(!m.server.has_client(m.client_color));
+    assert_eq!(m.server.take_freed_colors(), vec![m.client_color]);
+}
+
+#[test]
+fn leaving_clients_are_dropped_at_once() {
+    let mut m = Match::join_meddled(None, |_| {});
+    m.tick(true);
+    m.client.leave();
+    m.server.update(Some(m.server_color), &mut m.server_world, |_, _| {}, |_| {}).unwrap();
+    assert!(!m.server.has_client(m.client_color));
+    assert_eq!(m.server.take_freed_colors(), vec![m.client_color]);
+}
+
+// ticks until the client lost the server, which left
+fn lose_server(m: &mut Match) {
+    for _ in 0..100 {
+        // again and again, as it may be lost. Updates let the simulated network deliver it
+        m.server.leave();
+        m.server.update(Some(m.server_color), &mut m.server_world, |_, _| {}, |_| {}).unwrap();
+        let Match { client, client_world, client_color, .. } = m;
+        let result = client.update(
+            Some(*client_color),
+            &client_world.rules,
+            &mut client_world.entities,
+            |_| {},
+        );
+        match result {
+            Err(e) if e.lost_server() => return,
+            result => result.unwrap(),
+        }
+        thread::sleep(TICK);
+    }
+    panic!("the client did not notice the server leave");
+}
+
+#[test]
+fn a_player_hosts_from_where_the_server_left_off() {
+    let mut m = Match::join(&quick_lossy_sim());
+    for _ in 0..(HOST_STATE_TICKS + 50) {
+        m.tick(true);
+    }
+    let host_state = m.client.host_state.clone().expect("the client was not told how to host");
+    assert_eq!(
+        host_state.successors.iter().map(|&(color, _)| color).collect::<Vec<_>>(),
+        vec![m.client_color]
+    );
+    let server_entities = m.server_world.entities.clone();
+    let server_doors = m.server_world.doors.clone();
+    lose_server(&mut m);
+    let mut migration = m.client.migrate(Some(m.client_color)).unwrap();
+    match migration.poll().unwrap() {
+        Some(Migrated::Host(_, config, entities, doors)) => {
+            assert_eq!(config.player_color, m.client_color);
+            assert_eq!(entities.teleporters, server_entities.teleporters);
+            assert_eq!(doors, server_doors);
+        }
+        _ => panic!("the only successor did not host"),
+    }
+}
+
+#[test]
+fn migrating_does_not_block() {
+    let mut m = Match::join(&quick_lossy_sim());
+    for _ in 0..(HOST_STATE_TICKS + 50) {
+        m.tick(false);
+    }
+    lose_server(&mut m);
+    // someone before me in line, who never takes over
+    let silent = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let host_state = m.client.host_state.as_mut().unwrap();
+    host_state.successors.insert(0, (m.server_color, silent.local_addr().unwrap()));
+    let mut migration = m.client.migrate(Some(m.client_color)).unwrap();
+    let started = Instant::now();
+    let mut polls = 0;
+    let migrated = loop {
+        let polled_at = Instant::now();
+        let migrated = migration.poll().unwrap();
+        assert!(polled_at.elapsed() < Duration::from_millis(100), "polling blocked");
+        polls += 1;
+        match migrated {
+            Some(migrated) => break migrated,
+            None => thread::sleep(TICK),
+        }
+    };
+    assert!(matches!(migrated, Migrated::Host(..)));
+    assert!(started.elapsed() >= MIGRATION_PATIENCE, "did not wait for the first successor");
+    assert!(polls > 100);
+}
+
+#[test]
+fn spectators_do_not_learn_who_would_host() {
+    let sim = quick_lossy_sim();
+    let mut m = Match::join(&sim);
+    let config = IfClient { spectate: true, ..client_config() };
+    let (mut spectator, mut spectator_world, _) = join_while_serving(
+        &mut m.server,
+        &mut m.server_world,
+        m.server_color,
+        config,
+        Some(&sim),
+        |_| {},
+    )
+    .unwrap();
+    for _ in 0..(HOST_STATE_TICKS + 50) {
+        m.tick(false);
+        let World { rules, entities, .. } = &mut spectator_world;
+        spectator.update(None, rules, entities, |_| {}).unwrap();
+    }
+    assert!(m.client.host_state.is_some());
+    assert!(spectator.host_state.is_none());
+}
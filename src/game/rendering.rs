@@ -1,9 +1,11 @@
 use {
     crate::{
         game::{
+            chat::{MarkerAnchor, MAX_CHAT_LINES, MAX_LINE_CHARS, MAX_MARKERS},
+            font::{self, GLYPH_HEIGHT, GLYPH_PIXELS, GLYPH_WIDTH},
             room::{CELL_SIZE, HALF_ROOM_SIZE, ROOM_SIZE},
-            GameState, MyDoor, MAX_WALLS, NUM_DRAW_INFOS, NUM_MY_DOORS, NUM_PLAYERS,
-            NUM_TELEPORTERS, PLAYER_SIZE, TELEPORTER_SIZE, WALL_SIZE,
+            Door, GameState, PlayerColor, MAX_MY_DOORS, MAX_TELEPORTERS, MAX_WALLS, NUM_DRAW_INFOS,
+            NUM_PLAYERS,
         },
         prelude::*,
     },
@@ -16,13 +18,27 @@ use {
 
 pub const INSTANCE_RANGE_PLAYERS: Range<u32> = 0..NUM_PLAYERS;
 pub const INSTANCE_RANGE_TELEPORTERS: Range<u32> =
-    range_concat(INSTANCE_RANGE_PLAYERS, NUM_TELEPORTERS);
+    range_concat(INSTANCE_RANGE_PLAYERS, MAX_TELEPORTERS);
 pub const INSTANCE_RANGE_MY_DOORS: Range<u32> =
-    range_concat(INSTANCE_RANGE_TELEPORTERS, NUM_MY_DOORS);
-pub const INSTANCE_RANGE_WALLS: Range<u32> = range_concat(INSTANCE_RANGE_MY_DOORS, MAX_WALLS);
-pub const MAX_INSTANCES: u32 = INSTANCE_RANGE_WALLS.end;
+    range_concat(INSTANCE_RANGE_TELEPORTERS, MAX_MY_DOORS);
+pub const INSTANCE_RANGE_OPPONENT_DOORS: Range<u32> =
+    range_concat(INSTANCE_RANGE_MY_DOORS, MAX_MY_DOORS * (NUM_PLAYERS - 1));
+pub const INSTANCE_RANGE_WALLS: Range<u32> = range_concat(INSTANCE_RANGE_OPPONENT_DOORS, MAX_WALLS);
+pub const INSTANCE_RANGE_MARKERS: Range<u32> = range_concat(INSTANCE_RANGE_WALLS, MAX_MARKERS);
+pub const INSTANCE_RANGE_NET_STATS: Range<u32> =
+    range_concat(INSTANCE_RANGE_MARKERS, NUM_NET_STATS_BARS);
+pub const INSTANCE_RANGE_CHAT: Range<u32> = range_concat(INSTANCE_RANGE_NET_STATS, MAX_CHAT_PIXELS);
+pub const MAX_INSTANCES: u32 = INSTANCE_RANGE_CHAT.end;
 pub const VIEW_SIZE: Size = CELL_SIZE.scalar_mul(4);
 
+// round trip time, loss, snapshot age
+const NUM_NET_STATS_BARS: u32 = 3;
+// one instance per pixel of text, lit or not
+const MAX_CHAT_PIXELS: u32 = MAX_CHAT_LINES * MAX_LINE_CHARS * GLYPH_PIXELS;
+const WINDOW_SIZE: u32 = 900;
+// window pixels per pixel of text
+const TEXT_SCALE: f32 = 2.;
+
 // for debugging. true for release.
 pub const ENABLE_WRAP_DRAW: bool = true;
 
@@ -31,7 +47,7 @@ pub const ENABLE_WRAP_DRAW: bool = true;
 pub fn render_config() -> RendererConfig<'static> {
     RendererConfig {
         init: RendererInitConfig {
-            window_dims: Extent2D { width: 900, height: 900 },
+            window_dims: Extent2D { width: WINDOW_SIZE, height: WINDOW_SIZE },
             cull_face: Face::NONE,
             ..Default::default()
         },
@@ -81,6 +97,9 @@ impl GameState {
         self.update_teleporter_transforms(renderer);
         self.randomize_teleporter_tex_scissors(renderer);
         self.update_my_door_transforms(renderer);
+        self.update_markers_instances(renderer);
+        self.update_net_stats_transforms(renderer);
+        self.update_chat_instances(renderer);
     }
     pub fn update_view_transforms(&mut self) {
         const SCALE_XY: Vec2 = Vec2 {
@@ -88,7 +107,7 @@ impl GameState {
             y: 1. / VIEW_SIZE.arr[1] as f32,
         };
         let translations = {
-            let mut s = self.world.entities.players[self.controlling].pos.to_vec2();
+            let mut s = self.camera_pos().to_vec2();
             if ENABLE_WRAP_DRAW {
                 for idx in 0..2 {
                     if s[idx] < HALF_ROOM_SIZE.arr[idx] as f32 {
@@ -115,31 +134,159 @@ impl GameState {
         renderer.write_vertex_buffer(0, UNIT_QUAD.iter().copied());
     }
     fn update_wall_transforms<B: Backend>(&self, renderer: &mut Renderer<B>) {
+        let rules = &self.world.rules;
         let iter = self.world.room.iter_walls().map(move |(coord, dim)| {
             Mat4::from_translation(GameState::wall_pos(coord, dim).to_vec2().extend(0.1)) // BEHIND DOORS, ABOVE PLAYERS
-                * Mat4::from_scale(WALL_SIZE[dim].to_vec2().extend(1.))
+                * Mat4::from_scale(rules.wall_size(dim).to_vec2().extend(1.))
         });
         renderer.write_vertex_buffer(INSTANCE_RANGE_WALLS.start, iter);
     }
     fn update_my_door_transforms<B: Backend>(&self, renderer: &mut Renderer<B>) {
-        let iter = self.my_doors.iter().map(|&MyDoor { dim, coord, .. }| {
-            Mat4::from_translation(GameState::wall_pos(coord, dim).to_vec2().extend(0.)) // ABOVE WALLS
-                * Mat4::from_scale(WALL_SIZE[dim].to_vec2().extend(1.))
+        let hidden = Mat4::from_scale(Vec3::zero());
+        let owner = self.door_owner();
+        let iter = (0..MAX_MY_DOORS as usize).map(|i| {
+            // with fewer doors than the most, the rest stay hidden
+            let door = owner.and_then(|color| Some((color, *self.world.doors[color].get(i)?)));
+            match door {
+                // shut until the server moves it
+                Some((color, Door { dim, coord }))
+                    if !(Some(color) == self.controlling && self.used_doors.contains(i)) =>
+                {
+                    Mat4::from_translation(GameState::wall_pos(coord, dim).to_vec2().extend(0.)) // ABOVE WALLS
+                        * Mat4::from_scale(self.world.rules.wall_size(dim).to_vec2().extend(1.))
+                }
+                _ => hidden,
+            }
         });
         renderer.write_vertex_buffer(INSTANCE_RANGE_MY_DOORS.start, iter);
+        self.update_opponent_door_instances(renderer);
+    }
+    // slightly larger than their walls, and behind them. Only a rim in the owner's color shows
+    fn update_opponent_door_instances<B: Backend>(&self, renderer: &mut Renderer<B>) {
+        let hidden = Mat4::from_scale(Vec3::zero());
+        let count = MAX_MY_DOORS as usize * (NUM_PLAYERS as usize - 1);
+        let opponents = match self.door_owner() {
+            Some(owner) if self.world.rules.show_opponent_doors => owner.predator_prey(),
+            _ => {
+                let transforms = std::iter::repeat(hidden).take(count);
+                return renderer
+                    .write_vertex_buffer(INSTANCE_RANGE_OPPONENT_DOORS.start, transforms);
+            }
+        };
+        let doors = move |color: PlayerColor| {
+            self.world.doors[color].iter().map(move |&door| (color, door))
+        };
+        let opponent_doors = || doors(opponents[0]).chain(doors(opponents[1]));
+        let transforms = opponent_doors().map(|(_, Door { dim, coord })| {
+            let wall_size = self.world.rules.wall_size(dim);
+            let rim = wall_size[!dim] as f32 * 0.5;
+            let size = wall_size.to_vec2() + Vec2::new(rim, rim);
+            Mat4::from_translation(GameState::wall_pos(coord, dim).to_vec2().extend(0.15)) // BEHIND WALLS
+                * Mat4::from_scale(size.extend(1.))
+        });
+        // with fewer doors than the most, the rest stay hidden
+        let transforms = transforms.chain(std::iter::repeat(hidden)).take(count);
+        renderer.write_vertex_buffer(INSTANCE_RANGE_OPPONENT_DOORS.start, transforms);
+        let scissors =
+            opponent_doors().map(|(color, _)| scissor_for_tile_at([2 + color as u16, 0]));
+        renderer.write_vertex_buffer(INSTANCE_RANGE_OPPONENT_DOORS.start, scissors);
     }
     fn update_player_transforms<B: Backend>(&self, renderer: &mut Renderer<B>) {
+        let player_size = self.world.rules.player_size().to_vec2();
         let iter = self.world.entities.players.iter().map(move |player| {
             Mat4::from_translation(player.pos.to_vec2().extend(0.2)) // BEHIND WALLS
-                * Mat4::from_scale(PLAYER_SIZE.to_vec2().extend(1.))
+                * Mat4::from_scale(player_size.extend(1.))
         });
         renderer.write_vertex_buffer(INSTANCE_RANGE_PLAYERS.start, iter);
     }
+    fn update_markers_instances<B: Backend>(&self, renderer: &mut Renderer<B>) {
+        let hidden = Mat4::from_scale(Vec3::zero());
+        let player_size = self.world.rules.player_size().to_vec2();
+        let transforms = (0..MAX_MARKERS as usize).map(|i| match self.markers.get(i) {
+            None => hidden,
+            Some(marker) => {
+                let (pos, size) = match marker.anchor {
+                    MarkerAnchor::At(pos) => {
+                        // pulses, so it is not mistaken for a player
+                        let pulse = 0.75 + 0.25 * (marker.ticks_left as f32 * 0.3).sin();
+                        (pos.to_vec2(), player_size * pulse)
+                    }
+                    MarkerAnchor::Beside(color) => {
+                        let pos = self.world.entities.players[color].pos.to_vec2();
+                        (pos + player_size, player_size * 0.5)
+                    }
+                };
+                Mat4::from_translation(pos.extend(0.)) * Mat4::from_scale(size.extend(1.))
+            }
+        });
+        renderer.write_vertex_buffer(INSTANCE_RANGE_MARKERS.start, transforms);
+        let scissors = (0..MAX_MARKERS as usize).map(|i| {
+            match self.markers.get(i).and_then(|marker| marker.from) {
+                Some(color) => scissor_for_tile_at([2 + color as u16, 0]),
+                None => scissor_for_tile_at([1, 0]), // spectators have no color. looks like a door
+            }
+        });
+        renderer.write_vertex_buffer(INSTANCE_RANGE_MARKERS.start, scissors);
+    }
+    // bars along an edge of the view, each full when its stat is bad
+    fn update_net_stats_transforms<B: Backend>(&self, renderer: &mut Renderer<B>) {
+        let fills = match self.net_stats_overlay() {
+            None => [0.; NUM_NET_STATS_BARS as usize],
+            Some(stats) => [
+                stats.rtt_millis().unwrap_or(0) as f32 / 500.,
+                stats.loss * 4.,
+                stats.snapshot_age_ticks as f32 / 30.,
+            ],
+        };
+        let max_size =
+            Vec2::new(VIEW_SIZE[X] as f32, self.world.rules.player_size()[Y] as f32 * 0.25);
+        let corner = self.camera_pos().to_vec2() - VIEW_SIZE.to_vec2() * 0.9;
+        let transforms = fills.iter().enumerate().map(move |(i, fill)| {
+            let size = Vec2::new(max_size.x * fill.min(1.), max_size.y);
+            let pos = corner + Vec2::new(size.x * 0.5, max_size.y * 2. * i as f32);
+            Mat4::from_translation(pos.extend(0.)) * Mat4::from_scale(size.extend(1.))
+        });
+        renderer.write_vertex_buffer(INSTANCE_RANGE_NET_STATS.start, transforms);
+    }
+    // lines of text along the bottom of the view, each pixel a tile in the sender's color
+    fn update_chat_instances<B: Backend>(&self, renderer: &mut Renderer<B>) {
+        let hidden = Mat4::from_scale(Vec3::zero());
+        // the view spans twice `VIEW_SIZE` across the window
+        let pixel = VIEW_SIZE.to_vec2() * (2. * TEXT_SCALE / WINDOW_SIZE as f32);
+        let lines: Vec<_> = self.chat_lines().collect();
+        let bottom_left =
+            self.camera_pos().to_vec2() + VIEW_SIZE.to_vec2() * Vec2::new(-0.95, 0.95);
+        let mut pixels = vec![];
+        for (i, (from, text)) in lines.iter().enumerate() {
+            let lines_below = (lines.len() - 1 - i) as f32;
+            let top = bottom_left.y - pixel.y * (GLYPH_HEIGHT + 2) as f32 * (lines_below + 1.);
+            for (j, c) in text.chars().take(MAX_LINE_CHARS as usize).enumerate() {
+                let left = bottom_left.x + pixel.x * ((GLYPH_WIDTH + 1) as usize * j) as f32;
+                for [col, row] in font::lit_pixels(c) {
+                    let offset = Vec2::new(col as f32 + 0.5, row as f32 + 0.5) * pixel;
+                    pixels.push((Vec2::new(left, top) + offset, *from));
+                }
+            }
+        }
+        let transforms = pixels.iter().map(|&(pos, _)| {
+            Mat4::from_translation(pos.extend(0.)) * Mat4::from_scale(pixel.extend(1.))
+        });
+        let transforms = transforms.chain(std::iter::repeat(hidden)).take(MAX_CHAT_PIXELS as usize);
+        renderer.write_vertex_buffer(INSTANCE_RANGE_CHAT.start, transforms);
+        let scissors = pixels.iter().map(|&(_, from)| match from {
+            Some(color) => scissor_for_tile_at([2 + color as u16, 0]),
+            None => scissor_for_tile_at([1, 0]), // like spectators' markers
+        });
+        renderer.write_vertex_buffer(INSTANCE_RANGE_CHAT.start, scissors);
+    }
     fn update_teleporter_transforms<B: Backend>(&self, renderer: &mut Renderer<B>) {
+        let hidden = Mat4::from_scale(Vec3::zero());
+        let size = self.world.rules.teleporter_size().to_vec2();
         let iter = self.world.entities.teleporters.iter().map(move |pos| {
-            Mat4::from_translation(pos.to_vec2().extend(0.))
-                * Mat4::from_scale(TELEPORTER_SIZE.to_vec2().extend(1.))
+            Mat4::from_translation(pos.to_vec2().extend(0.)) * Mat4::from_scale(size.extend(1.))
         });
+        // with fewer teleporters than the most, the rest stay hidden
+        let iter = iter.chain(std::iter::repeat(hidden)).take(MAX_TELEPORTERS as usize);
         renderer.write_vertex_buffer(INSTANCE_RANGE_TELEPORTERS.start, iter);
     }
     fn randomize_teleporter_tex_scissors<B: Backend>(&mut self, renderer: &mut Renderer<B>) {
@@ -165,13 +312,18 @@ impl GameState {
         // my doors
         renderer.write_vertex_buffer(
             INSTANCE_RANGE_MY_DOORS.start,
-            repeat(scissor_for_tile_at([1, 0])).take(NUM_MY_DOORS as usize),
+            repeat(scissor_for_tile_at([1, 0])).take(MAX_MY_DOORS as usize),
         );
         // walls
         renderer.write_vertex_buffer(
             INSTANCE_RANGE_WALLS.start,
             repeat(scissor_for_tile_at([0, 0])).take(self.world.room.wall_count() as usize),
         );
+        // net stats bars
+        renderer.write_vertex_buffer(
+            INSTANCE_RANGE_NET_STATS.start,
+            (0..NUM_NET_STATS_BARS as u16).map(|x| scissor_for_tile_at([x + 2, 0])),
+        );
     }
 }
 
@@ -1,6 +1,6 @@
 use {
     crate::{
-        bit_set::{self, BitIndex, BitIndexSet},
+        bit_set::{self, BitIndex, BitIndexSet, FullBitIndexMap},
         prelude::*,
         rng::Rng,
         Dim,
@@ -19,6 +19,8 @@ pub const CELL_SIZE: Size = Size::new([
     (ROOM_SIZE.arr[1] / CELL_COUNTS.arr[1] as u32) as u16,
 ]);
 pub const HALF_CELL_SIZE: Size = CELL_SIZE.scalar_div(2);
+// bump whenever `Room::new` generates a different room for the same seed
+pub const MAZE_VERSION: u16 = 1;
 
 ///////////////////////////////////////////////
 // # Data types
@@ -26,7 +28,7 @@ pub const HALF_CELL_SIZE: Size = CELL_SIZE.scalar_div(2);
 pub struct Room {
     pub wall_sets: DimMap<BitIndexSet>,
 }
-#[derive(Default, Hash, Copy, Clone, Eq, PartialEq)]
+#[derive(Default, Hash, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Coord {
     // invariant: top_left corner
     pos: Pos,
@@ -42,6 +44,13 @@ struct CrossesWallInfo {
 pub struct ShortestPaths {
     map: FnvHashMap<[BitIndex; 2], u16>,
 }
+struct ArticulationSearch<'a> {
+    room: &'a Room,
+    visit_order: FullBitIndexMap<u16>, // u16::MAX until visited
+    lowest_reachable: FullBitIndexMap<u16>,
+    next_order: u16,
+    found: BitIndexSet,
+}
 
 /////////////////////
 
@@ -128,11 +137,20 @@ impl Room {
         }
         Room { wall_sets: incomplete_room.wall_sets }
     }
+    // cells without which the room falls apart. Whoever stands in one cuts off what's beyond
+    pub fn articulation_points(&self) -> BitIndexSet {
+        ArticulationSearch::run(self)
+    }
     pub fn iter_walls(&self) -> impl Iterator<Item = (Coord, Dim)> + '_ {
         let dimmed_iter =
             move |o| self.wall_sets[o].iter().map(move |bi| (Coord::from_bit_index(bi), o));
         dimmed_iter(X).chain(dimmed_iter(Y))
     }
+    // the wall (as indexed in `wall_sets`) between `coord` and its neighbour in `dir`
+    pub fn wall_between(coord: Coord, dir: Direction) -> (Coord, Dim) {
+        let cwi = dir.crosses_wall_info();
+        (if cwi.managed_by_src { coord } else { coord.stepped(dir) }, cwi.dim)
+    }
     pub fn wall_cells_to_check_at(
         mut pos: Pos,
         wall_dim: Dim,
@@ -170,9 +188,8 @@ impl Coord {
     // pub const DOMAIN_SIZE: u16 = TOT_CELL_COUNT;
     pub fn stepped_in_room(self, room: &Room, dir: Direction) -> Option<Self> {
         let dest = self.stepped(dir);
-        let cwi = dir.crosses_wall_info();
-        let bit_index = if cwi.managed_by_src { self } else { dest }.bit_index();
-        if room.wall_sets[cwi.dim].contains(bit_index) {
+        let (wall, dim) = Room::wall_between(self, dir);
+        if room.wall_sets[dim].contains(wall.bit_index()) {
             None
         } else {
             Some(dest)
@@ -281,3 +298,93 @@ impl ShortestPaths {
         Self { map }
     }
 }
+
+impl<'a> ArticulationSearch<'a> {
+    // Tarjan's algorithm
+    fn run(room: &'a Room) -> BitIndexSet {
+        let mut search = Self {
+            room,
+            visit_order: FullBitIndexMap::new_copied(u16::MAX),
+            lowest_reachable: FullBitIndexMap::new_copied(u16::MAX),
+            next_order: 0,
+            found: BitIndexSet::default(),
+        };
+        for bi in BitIndex::iter_domain() {
+            if search.visit_order[bi] == u16::MAX {
+                search.visit(bi, None);
+            }
+        }
+        search.found
+    }
+    // DFS from `at`. It's an articulation point IFF some subtree below it can't reach above it,
+    // or it's a root with 2+ subtrees
+    fn visit(&mut self, at: BitIndex, parent: Option<BitIndex>) {
+        self.visit_order[at] = self.next_order;
+        self.lowest_reachable[at] = self.next_order;
+        self.next_order += 1;
+        let mut subtrees = 0;
+        let coord = Coord::from_bit_index(at);
+        let room = self.room;
+        for dest in Direction::iter_domain().filter_map(|dir| coord.stepped_in_room(room, dir)) {
+            let dest = dest.bit_index();
+            if Some(dest) == parent {
+                continue;
+            }
+            if self.visit_order[dest] == u16::MAX {
+                subtrees += 1;
+                self.visit(dest, Some(at));
+                self.lowest_reachable[at] =
+                    self.lowest_reachable[at].min(self.lowest_reachable[dest]);
+                if parent.is_some() && self.lowest_reachable[dest] >= self.visit_order[at] {
+                    self.found.insert(at);
+                }
+            } else {
+                self.lowest_reachable[at] = self.lowest_reachable[at].min(self.visit_order[dest]);
+            }
+        }
+        if parent.is_none() && subtrees > 1 {
+            self.found.insert(at);
+        }
+    }
+}
+
+#[cfg(test)]
+impl Room {
+    // walls all around every cell, for tests to open as they like
+    pub fn walled() -> Self {
+        Self { wall_sets: DimMap::new([BitIndexSet::full(), BitIndexSet::full()]) }
+    }
+    pub fn open(&mut self, coord: Coord, dir: Direction) {
+        let (wall, dim) = Self::wall_between(coord, dir);
+        self.wall_sets[dim].remove(wall.bit_index());
+    }
+}
+#[cfg(test)]
+impl Coord {
+    pub fn from_cells(x: u16, y: u16) -> Self {
+        Self::from_bit_index(BitIndex(y * CELL_COUNTS[X] as u16 + x))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn articulation_points_cut_rooms_apart() {
+        let mut room = Room::walled();
+        // a T: (0,0) - (1,0) - (2,0), with (1,1) below its middle
+        room.open(Coord::from_cells(0, 0), Right);
+        room.open(Coord::from_cells(1, 0), Right);
+        room.open(Coord::from_cells(1, 0), Down);
+        // a loop of (4,0) (5,0) (5,1) (4,1), with (6,0) off to its side
+        room.open(Coord::from_cells(4, 0), Right);
+        room.open(Coord::from_cells(5, 0), Down);
+        room.open(Coord::from_cells(5, 1), Left);
+        room.open(Coord::from_cells(4, 1), Up);
+        room.open(Coord::from_cells(5, 0), Right);
+        let found: Vec<Coord> =
+            room.articulation_points().iter().map(Coord::from_bit_index).collect();
+        assert_eq!(found, vec![Coord::from_cells(1, 0), Coord::from_cells(5, 0)]);
+    }
+}
@@ -0,0 +1,92 @@
+use {
+    crate::{
+        game::{room::CELL_SIZE, Size, MAX_MY_DOORS, MAX_TELEPORTERS},
+        prelude::*,
+    },
+    std::{fs::File, io::Write, path::Path},
+};
+
+// what the server's players play by. Sent to clients as they join.
+// Sizes are in cells, in which players are round (cells aren't square in `Pos` units)
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Rules {
+    // ticks to cross a cell
+    pub move_speed: u16,
+    pub player_size: f32,
+    pub teleporter_size: f32,
+    pub wall_thickness: f32,
+    pub num_teleporters: u32,
+    // per player
+    pub num_my_doors: u32,
+    // a rule variant. Players see faint outlines of the others' doors
+    #[serde(default)]
+    pub show_opponent_doors: bool,
+}
+
+/////////////////////////////////
+fn cells_to_size(cells: DimMap<f32>) -> Size {
+    Size::new_xy_with(|dim| (cells[dim] * CELL_SIZE[dim] as f32) as u16)
+}
+impl Rules {
+    pub fn move_size(&self) -> Size {
+        CELL_SIZE.scalar_div(self.move_speed)
+    }
+    pub fn move_size_diag(&self) -> Size {
+        self.move_size().scalar_mul(5).scalar_div(7)
+    }
+    pub fn player_size(&self) -> Size {
+        cells_to_size(DimMap::new([self.player_size; 2]))
+    }
+    pub fn player_radius(&self) -> f32 {
+        self.player_size * 0.5
+    }
+    pub fn teleporter_size(&self) -> Size {
+        cells_to_size(DimMap::new([self.teleporter_size; 2]))
+    }
+    // a wall of `dim` spans its cell along `dim`
+    pub fn wall_size(&self, dim: Dim) -> Size {
+        let mut cells = DimMap::new([self.wall_thickness; 2]);
+        cells[dim] = 1.;
+        cells_to_size(cells)
+    }
+    // the longest step between wall checks while moving. Half the thinner of me and a wall, so
+    // that no step carries me past a wall's middle, from where I'd be pushed out its far side
+    pub fn max_sweep_step(&self) -> Size {
+        cells_to_size(DimMap::new([self.player_size.min(self.wall_thickness) * 0.5; 2]))
+    }
+    // false for rules that would break the game. e.g. a player larger than a cell would skip
+    // the walls beyond the neighbouring cells
+    pub fn is_valid(&self) -> bool {
+        let in_cell = |cells: f32| cells > 0. && cells < 1.;
+        self.move_speed >= 1
+            && self.move_size().arr.iter().all(|&val| val > 0)
+            && in_cell(self.player_size)
+            && self.max_sweep_step().arr.iter().all(|&val| val > 0)
+            && in_cell(self.teleporter_size)
+            && in_cell(self.wall_thickness * 2.)
+            && self.num_teleporters <= MAX_TELEPORTERS
+            && self.num_my_doors <= MAX_MY_DOORS
+    }
+    pub fn try_load_from(path: &Path) -> Option<Self> {
+        File::open(path).ok().and_then(|f| ron::de::from_reader(f).ok())
+    }
+    pub fn write_ron_into(&self, w: impl Write) {
+        ron::ser::to_writer_pretty(w, self, ron::ser::PrettyConfig::default()).unwrap();
+    }
+    pub fn try_save_into(&self, path: &Path) -> bool {
+        File::create(path).map(move |f| self.write_ron_into(f)).is_ok()
+    }
+}
+impl Default for Rules {
+    fn default() -> Self {
+        Self {
+            move_speed: 16,
+            player_size: 4. / 9.,
+            teleporter_size: 0.5,
+            wall_thickness: 1. / 7.,
+            num_teleporters: 2,
+            num_my_doors: 4,
+            show_opponent_doors: false,
+        }
+    }
+}
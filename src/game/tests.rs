@@ -0,0 +1,152 @@
+// players wandering random rooms
+use {
+    super::*,
+    ai::{PathLengthsAi, SinkAi},
+};
+
+fn random_world(seed: u64) -> (World, Rng) {
+    random_world_with(seed, Rules::default())
+}
+fn random_world_with(seed: u64, rules: Rules) -> (World, Rng) {
+    let (room, mut rng) = Room::new_seeded(seed);
+    let entities = Entities::random(&mut rng, &rules);
+    let doors = PlayerArr::new_with(|_| room.random_new_my_doors(&mut rng, &rules));
+    (World { room, entities, doors, rules }, rng)
+}
+fn random_vel(rng: &mut Rng) -> Vel {
+    Vel::new_xy_with(|_| [None, Some(Positive), Some(Negative)][rng.fastrand_rng.usize(..3)])
+}
+
+#[test]
+fn swept_players_cross_walls_only_through_their_doors() {
+    let mut stopped = 0;
+    for seed in 0..32 {
+        let (mut world, mut rng) = random_world(seed);
+        let mut vels = PlayerArr::new_with(|_| Vel::default());
+        for _ in 0..600 {
+            for color in PlayerColor::iter_domain() {
+                if rng.fastrand_rng.usize(..20) == 0 {
+                    vels[color] = random_vel(&mut rng);
+                }
+                let from = world.entities.players[color].pos;
+                let to = from.moved(&world.rules, vels[color], 1);
+                let at = world.swept(color, from, to);
+                assert!(
+                    world.path_is_open(color, from, at, None),
+                    "room {}: {:?} crossed a wall from {:?} to {:?}",
+                    seed,
+                    color,
+                    from,
+                    at
+                );
+                if at != to {
+                    stopped += 1;
+                }
+                world.entities.players[color].pos = at;
+            }
+        }
+    }
+    assert!(stopped > 0, "no wall was ever in the way");
+}
+
+#[test]
+fn the_controlled_player_crosses_walls_only_through_unused_doors() {
+    let color = PlayerColor::Black;
+    let (mut world, mut rng) = random_world(3);
+    let mut moving_through = Default::default();
+    let mut used_doors = MyDoorIndexSet::default();
+    let mut vel = Vel::default();
+    let mut stopped = 0;
+    // walk about
+    for _ in 0..3000 {
+        if rng.fastrand_rng.usize(..20) == 0 {
+            vel = random_vel(&mut rng);
+        }
+        let from = world.entities.players[color].pos;
+        let to = from.moved(&world.rules, vel, 1);
+        let passed_doors = world.swept_through_my_doors(color, to, used_doors, &mut moving_through);
+        let at = world.entities.players[color].pos;
+        assert!(
+            world.path_is_open(color, from, at, None),
+            "{:?} crossed a wall from {:?} to {:?}",
+            color,
+            from,
+            at
+        );
+        for i in (0..MAX_MY_DOORS as usize).filter(|&i| passed_doors.contains(i)) {
+            // as a client does, until the server moves the door
+            assert!(!used_doors.contains(i), "passed a used door");
+            used_doors.insert(i);
+        }
+        if at != to {
+            stopped += 1;
+        }
+    }
+    assert!(stopped > 0, "no wall was ever in the way");
+    // through each door and back. It stays shut once used
+    used_doors = MyDoorIndexSet::default();
+    for (i, door) in world.doors[color].clone().into_iter().enumerate() {
+        let (near, far) = (door.coord, door.coord.stepped((!door.dim).sign(Negative)));
+        // a cell's worth of steps. true if they passed the door
+        let mut sweep_across = |world: &mut World, used_doors, sign| {
+            let vel = Vel::new_xy_with(|dim| if dim == door.dim { None } else { Some(sign) });
+            let mut passed = false;
+            for _ in 0..world.rules.move_speed {
+                let to = world.entities.players[color].pos.moved(&world.rules, vel, 1);
+                passed |= world
+                    .swept_through_my_doors(color, to, used_doors, &mut moving_through)
+                    .contains(i);
+            }
+            passed
+        };
+        world.entities.players[color].pos = near.center_pos();
+        assert!(sweep_across(&mut world, used_doors, Negative), "door {:?} is shut", door);
+        let at = world.entities.players[color].pos;
+        assert_eq!(Coord::from_pos_flooring(at), far);
+        used_doors.insert(i);
+        assert!(!sweep_across(&mut world, used_doors, Positive));
+        let at = world.entities.players[color].pos;
+        assert_eq!(Coord::from_pos_flooring(at), far, "used door {:?} is open", door);
+    }
+}
+
+#[test]
+fn ais_play_without_teleporters() {
+    let rules = Rules { num_teleporters: 0, ..Rules::default() };
+    let (mut world, mut rng) = random_world_with(1, rules);
+    let mut ais: Vec<Box<dyn Ai>> = vec![
+        Box::new(SinkAi::new(PlayerColor::Black, &world, &mut rng)),
+        Box::new(PathLengthsAi::new(PlayerColor::Blue, &world, &mut rng)),
+        Box::new(MiniMaxAi::new(PlayerColor::Orange, &world, &mut rng)),
+    ];
+    for _ in 0..200 {
+        for (ai, color) in ais.iter_mut().zip(PlayerColor::iter_domain()) {
+            let vel = ai.update(&world, &mut rng);
+            let from = world.entities.players[color].pos;
+            let to = from.moved(&world.rules, vel, 1);
+            world.entities.players[color].pos = world.swept(color, from, to);
+        }
+    }
+}
+
+#[test]
+fn every_kind_of_ai_plays() {
+    use AiKind::*;
+    for &kind in [MiniMax, Sink, PathLengths, Team].iter() {
+        let (mut world, mut rng) = random_world(2);
+        let started_at = world.entities.players.clone();
+        let mut ais: Vec<Box<dyn Ai>> =
+            PlayerColor::iter_domain().map(|color| new_ai(kind, color, &world, &mut rng)).collect();
+        for _ in 0..300 {
+            for (ai, color) in ais.iter_mut().zip(PlayerColor::iter_domain()) {
+                let vel = ai.update(&world, &mut rng);
+                let from = world.entities.players[color].pos;
+                let to = from.moved(&world.rules, vel, 1);
+                world.entities.players[color].pos = world.swept(color, from, to);
+            }
+        }
+        let moved = PlayerColor::iter_domain()
+            .any(|color| world.entities.players[color].pos != started_at[color].pos);
+        assert!(moved, "{:?} AIs stood still", kind);
+    }
+}
@@ -7,7 +7,13 @@ mod wrap_int;
 
 use {
     crate::{
-        game::{config::Config, rendering::render_config, GameState},
+        game::{
+            config::{Config, NetSimConfig},
+            lobby,
+            rendering::render_config,
+            rules::Rules,
+            GameState,
+        },
         prelude::*,
     },
     gfx_2020::{gfx_hal::Backend, *},
@@ -26,9 +32,7 @@ extern crate gfx_backend_metal as back;
 extern crate gfx_backend_vulkan as back;
 /////////////////////////////////
 
-pub(crate) fn game_state_init_fn<B: Backend>(
-    renderer: &mut Renderer<B>,
-) -> ProceedWith<&'static mut GameState> {
+fn load_config() -> Config {
     let maybe_arg = std::env::args().nth(1);
     let config_path = if let Some(arg) = maybe_arg.as_ref() {
         Path::new(arg)
@@ -48,9 +52,57 @@ pub(crate) fn game_state_init_fn<B: Backend>(
         writeln!(stdio, "Beginning game with config ").unwrap();
         config.write_ron_into(stdio);
     }
-    Ok(Box::leak(Box::new(GameState::new(renderer, &config))))
+    config
+}
+
+// only the server's rules matter. Clients play by those
+fn load_rules() -> Rules {
+    let maybe_arg = std::env::args().nth(2);
+    let rules_path = if let Some(arg) = maybe_arg.as_ref() {
+        Path::new(arg)
+    } else {
+        Path::new("./honeydew_rules.ron")
+    };
+    let rules = Rules::try_load_from(rules_path).unwrap_or_else(move || {
+        println!("No rules found at {:?}. Generating default!", rules_path.canonicalize());
+        let rules = Rules::default();
+        rules.try_save_into(rules_path);
+        rules
+    });
+    if !rules.is_valid() {
+        println!("Invalid rules at {:?}. Using the defaults!", rules_path);
+        return Rules::default();
+    }
+    rules
+}
+
+pub(crate) fn game_state_init_fn<B: Backend>(
+    renderer: &mut Renderer<B>,
+    config: &Config,
+    rules: &Rules,
+) -> ProceedWith<&'static mut GameState> {
+    match GameState::new(renderer, config, rules) {
+        Ok(game_state) => Ok(Box::leak(Box::new(game_state))),
+        Err(e) => {
+            println!("Failed to join: {}", e);
+            Err(HaltLoop)
+        }
+    }
 }
 
 fn main() {
-    gfx_2020::main_loop::<back::Backend, _, _>(&render_config(), game_state_init_fn);
+    let config = load_config();
+    // clients play by their server's rules, and need no file of their own
+    let rules = if config.server_mode || config.if_lobby.is_some() {
+        load_rules()
+    } else {
+        Rules::default()
+    };
+    if let Some(if_lobby) = &config.if_lobby {
+        let net_sim = NetSimConfig::from_env().or_else(|| config.net_sim.clone());
+        return lobby::run(if_lobby, &rules, net_sim.as_ref());
+    }
+    gfx_2020::main_loop::<back::Backend, _, _>(&render_config(), move |renderer| {
+        game_state_init_fn(renderer, &config, &rules)
+    });
 }